@@ -7,13 +7,44 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::io;
+use std::io::Read;
 use std::error::FromError;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
 use std::num::Int;
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+#[cfg(feature = "image-crate")]
+use image;
+
+#[cfg(feature = "png")]
+use png;
+
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[cfg(feature = "gzip")]
+use flate2::read::GzDecoder;
+
+/// The resolution assumed for images that do not declare one
+pub const DEFAULT_DPI: u32 = 72;
+
+/// The maximum number of cells of the tables made by `Bitmap::to_html_table`
+pub const MAX_HTML_CELLS: u32 = 4096;
 
 /// A BMP header
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Header {
     pub size: u32,
     pub reserved: u32,
@@ -21,9 +52,11 @@ pub struct Header {
 }
 
 /// A BMP header
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Dib {
     pub width: u32,
+    /// The number of rows, made positive for top-down images
     pub height: u32,
     pub planes: u16,
     pub bpp: u16,
@@ -33,35 +66,215 @@ pub struct Dib {
     pub ppm_y: u32,
     pub colors: u32,
     pub imp_colors: u32,
+    /// The red, green and blue channel masks, present in V2 and later headers
+    pub rgb_masks: Option<(u32, u32, u32)>,
+    /// The alpha channel mask, present in V3 and later headers
+    pub alpha_mask: Option<u32>,
+    /// The red, green and blue gammas, present in V4 and later headers
+    pub gamma: Option<(f64, f64, f64)>,
+    /// The rendering intent, present in V5 headers
+    pub intent: Option<RenderingIntent>,
+    /// Whether the rows are stored top-down, as declared by a negative height
+    pub top_down: bool,
+    /// The header bytes following the 40 bytes of BITMAPINFOHEADER, if any
+    pub extra: Vec<u8>,
+    /// The version of the header this DIB was read from
+    pub version: HeaderVersion,
+}
+
+impl Dib {
+
+    /// The number of leading color table entries declared important, being 0 all of them
+    pub fn important_colors(&self) -> u32 {
+        self.imp_colors
+    }
+
+    /// Iterate over the DIB fields as name-value pairs
+    pub fn fields(&self) -> impl Iterator<Item = (&'static str, u64)> {
+        vec![
+            ("width", self.width as u64),
+            ("height", self.height as u64),
+            ("planes", self.planes as u64),
+            ("bpp", self.bpp as u64),
+            ("comp", self.comp as u64),
+            ("size", self.size as u64),
+            ("ppm_x", self.ppm_x as u64),
+            ("ppm_y", self.ppm_y as u64),
+            ("colors", self.colors as u64),
+            ("imp_colors", self.imp_colors as u64),
+        ].into_iter()
+    }
+}
+
+/// A summary of the format and contents of an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageStats {
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u16,
+    /// The number of distinct resolved colors, alpha included
+    pub distinct_colors: usize,
+    pub min_luminance: u8,
+    pub max_luminance: u8,
+    pub mean_luminance: f64,
+    pub has_transparency: bool,
+}
+
+/// The version of the DIB header, as told by its size
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum HeaderVersion {
+    /// BITMAPCOREHEADER, 12 bytes with 16-bit dimensions, followed by a color table
+    /// of 3 byte entries
+    Core,
+    /// BITMAPINFOHEADER, 40 bytes, or its 52 bytes extension with RGB masks
+    Info,
+    /// BITMAPV3INFOHEADER, 56 bytes
+    V3,
+    /// BITMAPV4HEADER, 108 bytes
+    V4,
+    /// BITMAPV5HEADER, 124 bytes
+    V5,
+}
+
+/// The corner of the image where the stored pixels start
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    TopLeft,
+    BottomLeft,
+}
+
+/// The rendering intent of a V5 header
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RenderingIntent {
+    /// LCS_GM_BUSINESS
+    Saturation,
+    /// LCS_GM_GRAPHICS
+    RelativeColorimetric,
+    /// LCS_GM_IMAGES
+    Perceptual,
+    /// LCS_GM_ABS_COLORIMETRIC
+    AbsoluteColorimetric,
+    Unknown(u32),
+}
+
+impl RenderingIntent {
+
+    fn from_u32(n: u32) -> RenderingIntent {
+        match n {
+            1 => RenderingIntent::Saturation,
+            2 => RenderingIntent::RelativeColorimetric,
+            4 => RenderingIntent::Perceptual,
+            8 => RenderingIntent::AbsoluteColorimetric,
+            _ => RenderingIntent::Unknown(n),
+        }
+    }
 }
 
 /// A type to represent the color un RGBX format
-#[derive(Debug)]
-pub struct Rgbx(u8, u8, u8, u8);
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rgbx(pub u8, pub u8, pub u8, pub u8);
 
 impl Rgbx {
 
+    /// Pack this color into a direct-color pixel value
+    pub fn to_pixel(&self) -> usize {
+        ((self.0 as usize) << 24) | ((self.1 as usize) << 16) | 
+        ((self.2 as usize) << 8) | (self.3 as usize)
+    }
+
+    /// The luminance of this color (ITU-R BT.601)
+    fn luminance(&self) -> u8 {
+        ((299 * self.0 as u32 + 587 * self.1 as u32 + 114 * self.2 as u32) / 1000) as u8
+    }
+
+    /// The squared euclidean distance to another color in RGB space
+    fn distance(&self, other: &Rgbx) -> u32 {
+        let d = |a: u8, b: u8| (a as i32 - b as i32) * (a as i32 - b as i32);
+        (d(self.0, other.0) + d(self.1, other.1) + d(self.2, other.2)) as u32
+    }
+
+    /// The value of the given channel, being alpha the fourth component
+    pub fn channel(&self, ch: Channel) -> u8 {
+        match ch {
+            Channel::Red => self.0,
+            Channel::Green => self.1,
+            Channel::Blue => self.2,
+            Channel::Alpha => self.3,
+        }
+    }
+
+    /// Unpack a color from a direct-color pixel value
+    pub fn from_pixel(p: usize) -> Rgbx { Rgbx(
+        (p >> 24) as u8,
+        (p >> 16) as u8,
+        (p >> 8) as u8,
+        p as u8,
+    )}
+
+    /// The red channel
+    pub fn r(&self) -> u8 { self.0 }
+
+    /// The green channel
+    pub fn g(&self) -> u8 { self.1 }
+
+    /// The blue channel
+    pub fn b(&self) -> u8 { self.2 }
+
+    /// The fourth channel (reserved in color tables, alpha in resolved colors)
+    pub fn x(&self) -> u8 { self.3 }
+
+    /// Convert a color table entry, already decoded from its little-endian 
+    /// `[B, G, R, X]` bytes into a native integer.
     fn from_bgrx_u32(n: u32) -> Rgbx { Rgbx(
-        (n.to_be() >> 8) as u8,
-        (n.to_be() >> 16) as u8,
-        (n.to_be() >> 24) as u8,
-        (n.to_be() >> 0) as u8,
+        (n >> 16) as u8,
+        (n >> 8) as u8,
+        (n >> 0) as u8,
+        (n >> 24) as u8,
     )}
 }
 
+/// A color channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+}
+
+/// The order of the channels of a packed color
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+    Rgba,
+    Bgra,
+}
+
 /// The color table of a BMP
 pub type ColorTable = Vec<Rgbx>;
 
-/// BMP pixel data
+/// BMP pixel data. Each pixel is an index in the color table for images up to
+/// 8 bpp, or a packed RGBX color for direct-color images.
 pub type Pixels = Vec<usize>;
 
 /// A BMP bitmap
-#[derive(Debug)] 
+#[derive(Debug, Clone, PartialEq)] 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Bitmap {
     pub header: Header,
     pub dib: Dib,
     pub colors: ColorTable,
     pub pixels: Pixels,
+    /// The raw fourth byte of each pixel of a 32 bpp file, in pixel order
+    pub raw_alpha: Option<Vec<u8>>,
+    /// The DIB header exactly as read, including any fields that `dib` does not
+    /// represent. It is empty for bitmaps not read from a file and is dropped when
+    /// the format or dimensions change, so it always describes the pixels.
+    pub raw_dib: Vec<u8>,
 }
 
 /// A BMP load error
@@ -72,6 +285,67 @@ pub enum LoadError {
     BadMagic,
     UnsupportedDib,
     UnsupportedBpp,
+    InvalidPnm,
+    MissingPalette,
+    /// The headers and color table (ending at the first offset) overlap the
+    /// pixel data (starting at the second offset)
+    CorruptLayout(u64, u64),
+    /// The declared image size (first) disagrees with the size of the padded
+    /// pixel rows (second)
+    SizeMismatch(u64, u64),
+    /// The input has no data at all
+    EmptyInput,
+    /// The pixels use a compression method that cannot be decoded
+    UnsupportedCompression(u32),
+    /// The size of the pixel data does not fit in memory
+    DimensionsTooLarge,
+    /// The output buffer takes the second number of bytes but the image needs
+    /// the first
+    BufferTooSmall(usize, usize),
+    /// The number of color planes is not 1
+    InvalidPlanes(u16),
+}
+
+/// Options controlling how a bitmap is decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    /// Reject files whose headers are inconsistent even if they can be decoded
+    pub strict: bool,
+    /// The byte order of the multi-byte header fields
+    pub byte_order: ByteOrder,
+    /// The number of bytes pixel rows are padded to a multiple of, 4 in standard files
+    pub row_alignment: usize,
+    /// Recover from inconsistent headers where the intended values can be inferred
+    pub lenient: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions { 
+        DecodeOptions { 
+            strict: false, 
+            byte_order: ByteOrder::Little, 
+            row_alignment: 4, 
+            lenient: false,
+        } 
+    }
+}
+
+/// Options controlling how the colors of an image are reduced
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct QuantizeOptions {
+    /// Diffuse the error of each remapped pixel to its neighbors (Floyd-Steinberg)
+    pub dither: bool,
+}
+
+/// The byte order of multi-byte fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl Default for ByteOrder {
+    fn default() -> ByteOrder { ByteOrder::Little }
 }
 
 impl FromError<io::Error> for LoadError {
@@ -80,6 +354,135 @@ impl FromError<io::Error> for LoadError {
     }
 }
 
+/// A scanline extraction error
+#[derive(Debug)]
+pub enum ScanlineError {
+    RowOutOfRange,
+    UnsupportedBpp,
+    IndexOverflow,
+}
+
+/// A row interleaving error
+#[derive(Debug)]
+pub enum InterleaveError {
+    WidthMismatch,
+    HeightMismatch,
+    PaletteMismatch,
+}
+
+/// An image concatenation error
+#[derive(Debug)]
+pub enum ConcatError {
+    WidthMismatch,
+    HeightMismatch,
+}
+
+/// A canvas padding error
+#[derive(Debug)]
+pub enum PadError {
+    TargetTooSmall,
+}
+
+/// Where an image is placed within a larger canvas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    Center,
+    TopLeft,
+}
+
+/// A color table manipulation error
+#[derive(Debug)]
+pub enum PaletteError {
+    RangeOutOfBounds,
+}
+
+/// A region copy error
+#[derive(Debug)]
+pub enum BlitError {
+    FormatMismatch,
+}
+
+/// A subsampling error
+#[derive(Debug)]
+pub enum SubsampleError {
+    ZeroFactor,
+}
+
+/// A tiling error
+#[derive(Debug)]
+pub enum TileError {
+    DimensionsTooLarge,
+}
+
+/// A bitmap construction error
+#[derive(Debug)]
+pub enum BuildError {
+    RowWidthMismatch,
+    TooManyColors,
+    UnsupportedBpp,
+    /// Direct color images (more than 8 bpp) cannot have a color table
+    PaletteOnDirectImage,
+    MissingPalette,
+    PixelCountMismatch,
+    /// A pixel refers to a color beyond the color table
+    IndexOverflow,
+}
+
+/// A bitmap saving error
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    UnsupportedBpp,
+    /// The image has more colors than the requested bpp can index
+    TooManyColors,
+}
+
+impl FromError<io::Error> for SaveError {
+    fn from_error(err: io::Error) -> SaveError {
+        SaveError::Io(err)
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &SaveError::Io(ref cause) => 
+                write!(f, "unexpected IO error: {}", cause),
+            &SaveError::UnsupportedBpp => 
+                write!(f, "unsupported bits per pixel (1, 2, 4, 8, 24 or 32 bpp supported)"),
+            &SaveError::TooManyColors => 
+                write!(f, "too many colors for the requested bits per pixel"),
+        }
+    }
+}
+
+/// A PNG encoding error
+#[cfg(feature = "png")]
+#[derive(Debug)]
+pub enum EncodeError {
+    Png(png::EncodingError),
+    TooManyColors,
+}
+
+#[cfg(feature = "png")]
+impl FromError<png::EncodingError> for EncodeError {
+    fn from_error(err: png::EncodingError) -> EncodeError {
+        EncodeError::Png(err)
+    }
+}
+
+#[cfg(feature = "png")]
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &EncodeError::Png(ref cause) => 
+                write!(f, "unexpected PNG error: {}", cause),
+            &EncodeError::TooManyColors => 
+                write!(f, "too many colors for an indexed PNG (256 at most)"),
+        }
+    }
+}
+
 impl fmt::Display for LoadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
@@ -90,171 +493,3117 @@ impl fmt::Display for LoadError {
             &LoadError::BadMagic => 
                 write!(f, "invalid magic number in BMP header"),
             &LoadError::UnsupportedDib => 
-                write!(f, "unsupported DIP block (only BITMAPINFOHEADER is supported)"),
+                write!(f, "unsupported DIP block (BITMAPCOREHEADER or BITMAPINFOHEADER required)"),
             &LoadError::UnsupportedBpp => 
-                write!(f, "unsupported bits per pixel (only 4 bpp supported)"),
+                write!(f, "unsupported bits per pixel (only 1, 4, 24, 32 and 64 bpp supported)"),
+            &LoadError::InvalidPnm => 
+                write!(f, "invalid data in PNM file"),
+            &LoadError::MissingPalette => 
+                write!(f, "missing color table in indexed image"),
+            &LoadError::CorruptLayout(end, offset) => 
+                write!(f, "corrupt layout (headers end at byte {} but pixels start at byte {})",
+                    end, offset),
+            &LoadError::SizeMismatch(declared, expected) => 
+                write!(f, "image size is {} bytes but pixel rows take {} bytes",
+                    declared, expected),
+            &LoadError::EmptyInput => 
+                write!(f, "empty input"),
+            &LoadError::UnsupportedCompression(comp) => 
+                write!(f, "unsupported compression method {}", comp),
+            &LoadError::DimensionsTooLarge => 
+                write!(f, "image dimensions too large"),
+            &LoadError::BufferTooSmall(needed, len) => 
+                write!(f, "output buffer of {} bytes cannot hold {} bytes", len, needed),
+            &LoadError::InvalidPlanes(planes) => 
+                write!(f, "invalid number of color planes {} (must be 1)", planes),
+        }
+    }
+}
+
+impl fmt::Display for ScanlineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ScanlineError::RowOutOfRange => 
+                write!(f, "row out of range"),
+            &ScanlineError::UnsupportedBpp => 
+                write!(f, "unsupported bits per pixel (1, 2, 4, 8, 24 or 32 bpp supported)"),
+            &ScanlineError::IndexOverflow => 
+                write!(f, "pixel index does not fit in the requested bits per pixel"),
+        }
+    }
+}
+
+impl fmt::Display for InterleaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &InterleaveError::WidthMismatch => 
+                write!(f, "images have different widths"),
+            &InterleaveError::HeightMismatch => 
+                write!(f, "images have different heights"),
+            &InterleaveError::PaletteMismatch => 
+                write!(f, "images have different pixel formats or color tables"),
+        }
+    }
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ConcatError::WidthMismatch => 
+                write!(f, "images have different widths"),
+            &ConcatError::HeightMismatch => 
+                write!(f, "images have different heights"),
+        }
+    }
+}
+
+impl fmt::Display for PadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &PadError::TargetTooSmall => 
+                write!(f, "target dimensions are smaller than the image"),
+        }
+    }
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &PaletteError::RangeOutOfBounds => 
+                write!(f, "range exceeds the color table"),
+        }
+    }
+}
+
+impl fmt::Display for BlitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &BlitError::FormatMismatch => 
+                write!(f, "images have different pixel formats or color tables"),
+        }
+    }
+}
+
+impl fmt::Display for SubsampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &SubsampleError::ZeroFactor => 
+                write!(f, "subsample factor must be positive"),
+        }
+    }
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &TileError::DimensionsTooLarge => 
+                write!(f, "tiled dimensions do not fit in 32 bits"),
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &BuildError::RowWidthMismatch => 
+                write!(f, "rows have different widths"),
+            &BuildError::TooManyColors => 
+                write!(f, "more than 256 distinct colors"),
+            &BuildError::UnsupportedBpp => 
+                write!(f, "unsupported bits per pixel (1, 2, 4, 8, 24 or 32 bpp supported)"),
+            &BuildError::PaletteOnDirectImage => 
+                write!(f, "direct color image with a color table"),
+            &BuildError::MissingPalette => 
+                write!(f, "missing color table in indexed image"),
+            &BuildError::PixelCountMismatch => 
+                write!(f, "number of pixels does not match the dimensions"),
+            &BuildError::IndexOverflow => 
+                write!(f, "pixel index out of the color table"),
         }
     }
 }
 
 macro_rules! word {
-    ($b:expr, $i:expr) => (Int::from_le($b[$i] as u16 | (($b[$i+1] as u16) << 8)))
+    ($b:expr, $i:expr) => (Int::from_le($b[$i] as u16 | (($b[$i+1] as u16) << 8)));
+    ($b:expr, $i:expr, $o:expr) => (match $o {
+        ByteOrder::Little => word!($b, $i),
+        ByteOrder::Big => (($b[$i] as u16) << 8) | $b[$i+1] as u16,
+    })
 }
 
 macro_rules! dword {
     ($b:expr, $i:expr) => (
         Int::from_le($b[$i] as u32 | (($b[$i+1] as u32) << 8) | 
-        (($b[$i+2] as u32) << 16) | (($b[$i+3] as u32) << 24)))
+        (($b[$i+2] as u32) << 16) | (($b[$i+3] as u32) << 24)));
+    ($b:expr, $i:expr, $o:expr) => (match $o {
+        ByteOrder::Little => dword!($b, $i),
+        ByteOrder::Big => (($b[$i] as u32) << 24) | (($b[$i+1] as u32) << 16) | 
+            (($b[$i+2] as u32) << 8) | $b[$i+3] as u32,
+    })
+}
+
+fn put_word(buff: &mut Vec<u8>, n: u16) {
+    buff.push(n as u8);
+    buff.push((n >> 8) as u8);
+}
+
+fn put_dword(buff: &mut Vec<u8>, n: u32) {
+    buff.push(n as u8);
+    buff.push((n >> 8) as u8);
+    buff.push((n >> 16) as u8);
+    buff.push((n >> 24) as u8);
+}
+
+/// Check whether the given bytes look like a supported BMP file without decoding it.
+pub fn is_bmp(data: &[u8]) -> bool {
+    if data.len() < 18 || data[0] != 0x42 || data[1] != 0x4d { return false }
+    is_supported_dib_size(dword!(data, 14))
+}
+
+/// Whether a DIB of the given declared size can be decoded: a BITMAPCOREHEADER, or
+/// at least a BITMAPINFOHEADER with extra bytes up to the size of a V5 header.
+fn is_supported_dib_size(size: u32) -> bool {
+    match size {
+        12 | 40...124 => true,
+        _ => false,
+    }
 }
 
 impl Bitmap {
 
     /// Load a bitmap from the given file. 
-    pub fn load(filename: &str) -> Result<Bitmap, LoadError> {
+    pub fn load(filename: &str) -> ::Result<Bitmap> {
         let mut file = try!(fs::File::open(filename));
         Bitmap::read(&mut file)
     }
 
-    /// Read a bitmap
-    pub fn read<R: io::Read>(input: &mut R) -> Result<Bitmap, LoadError> {
-        let mut binput = io::BufReader::new(input);
-        let hd = try!(Bitmap::read_header(&mut binput));
-        let dib = try!(Bitmap::read_dib(&mut binput));
-        let ct = try!(Bitmap::read_color_table(&mut binput, dib.colors as usize));
-        let pixels = try!(Bitmap::read_pixels(
-            &mut binput, dib.width as usize, dib.height as usize, dib.bpp));
-        Ok(Bitmap { header: hd, dib: dib , colors: ct, pixels: pixels })
+    /// Load a bitmap from the given gzip-compressed file.
+    #[cfg(feature = "gzip")]
+    pub fn load_gz<P: AsRef<Path>>(path: P) -> ::Result<Bitmap> {
+        let file = try!(fs::File::open(path));
+        Bitmap::read(&mut GzDecoder::new(file))
     }
 
-    fn read_section<R: io::Read>(input: &mut R, ebytes: usize) -> Result<Vec<u8>, LoadError> {
-        let mut buff = vec![0u8; ebytes];
-        let nbytes = try!(input.read(&mut buff));        
-        
-        if nbytes != ebytes { Err(LoadError::UnexpectedEof)}
-        else { Ok(buff) }
+    /// Read a bitmap
+    pub fn read<R: io::Read>(input: &mut R) -> ::Result<Bitmap> {
+        Bitmap::read_with_options(input, &DecodeOptions::default())
     }
 
-    fn read_header<R: io::Read>(input: &mut R) -> Result<Header, LoadError> {
-        let buff = try!(Bitmap::read_section(input, 14));
-
-        // First two bytes must be `BM` in ASCII
-        if buff[0] != 0x42 || buff[1] != 0x4d { return Err(LoadError::BadMagic)}
-
-        // Read the size, reserved and offset
-        let size = dword!(buff, 2);
-        let reserved = dword!(buff, 6);
-        let offset = dword!(buff, 10);
+    /// Read a bitmap starting at byte `start` of the input, as when it is embedded
+    /// in a larger file.
+    pub fn read_at<R: io::Read + io::Seek>(input: &mut R, start: u64) -> ::Result<Bitmap> {
+        try!(input.seek(io::SeekFrom::Start(start)));
+        Bitmap::read(input)
+    }
 
-        Ok(Header { size: size, reserved: reserved, offset: offset })
+    /// Read a bitmap with the given decoding options
+    pub fn read_with_options<R: io::Read>(
+            input: &mut R, opts: &DecodeOptions) -> ::Result<Bitmap> {
+        let mut pixels = Pixels::new();
+        let pre = try!(Bitmap::read_parts(input, &mut pixels, opts, &mut |_| {}));
+        Ok(pre.into_bitmap(pixels))
     }
 
-    fn read_dib<R: io::Read>(input: &mut R) -> Result<Dib, LoadError> {
-        let buff = try!(Bitmap::read_section(input, 40));
+    /// Read a bitmap decoding its pixels into the given buffer, which is cleared 
+    /// and refilled so its allocation can be reused across decodes.
+    pub fn read_into<R: io::Read>(
+            input: &mut R, buf: &mut Pixels) -> ::Result<(Header, Dib, ColorTable)> {
+        let pre = try!(Bitmap::read_parts(input, buf, &DecodeOptions::default(), &mut |_| {}));
+        Bitmap::split_alpha(buf, &pre.dib);
+        Ok((pre.header, pre.dib, pre.colors))
+    }
 
-        // The indicated DIB length must be 40
-        if dword!(buff, 0) != 40 { return Err(LoadError::UnsupportedDib)}
+    /// Start reading a bitmap decoding at most `max_rows` pixel rows. The input is 
+    /// read without buffering, so decoding can go on later with `resume_partial` 
+    /// from where it stops.
+    pub fn read_partial<R: io::Read>(
+            input: &mut R, max_rows: u32) -> ::Result<DecodeState> {
+        Bitmap::read_partial_with_options(input, max_rows, &DecodeOptions::default())
+    }
 
-        // Read the fields
-        let width = dword!(buff, 4);
-        let height = dword!(buff, 8);
-        let planes = word!(buff, 12);
-        let bpp = word!(buff, 14);
-        let compression = dword!(buff, 16);
-        let size = dword!(buff, 20);
-        let ppm_x = dword!(buff, 24);
-        let ppm_y = dword!(buff, 28);
-        let colors = dword!(buff, 32);
-        let imp_colors = dword!(buff, 36);
-
-        Ok(Dib { 
-            width: width, 
-            height: height, 
-            planes: planes,
-            bpp: bpp,
-            comp: compression,
-            size: size,
-            ppm_x: ppm_x,
-            ppm_y: ppm_y,
-            colors: colors,
-            imp_colors: imp_colors,
-        })
+    /// Start reading a bitmap as `read_partial` does with the given decoding options
+    pub fn read_partial_with_options<R: io::Read>(
+            input: &mut R, max_rows: u32, opts: &DecodeOptions) -> ::Result<DecodeState> {
+        let pre = try!(Bitmap::read_prelude(input, true, opts));
+        let state = DecodeState { 
+            prelude: pre, align: opts.row_alignment, pixels: Pixels::new(), rows: 0 
+        };
+        Bitmap::resume_partial(input, state, max_rows)
     }
 
-    fn read_color_table<R: io::Read>(
-            input: &mut R, ncolors: usize) -> Result<ColorTable, LoadError> {
-        let buff = try!(Bitmap::read_section(input, 4*ncolors));
-        let mut table = ColorTable::new();
-        for i in 0..ncolors {
-            table.push(Rgbx::from_bgrx_u32(dword!(buff, 4*i)));
+    /// Decode at most `max_rows` more pixel rows of a bitmap started by `read_partial`
+    pub fn resume_partial<R: io::Read>(
+            input: &mut R, mut state: DecodeState, max_rows: u32) -> ::Result<DecodeState> {
+        let decoder = try!(Bitmap::row_decoder(&state.prelude.dib));
+        let cols = state.prelude.dib.width as usize;
+        let nbytes = try!(decoder.stride(cols).ok_or(LoadError::DimensionsTooLarge));
+        let (rbytes, _) = try!(Bitmap::aligned_section(nbytes, 1, state.align));
+        let mut left = max_rows;
+        while left > 0 && !state.is_complete() {
+            let row = try!(Bitmap::read_section(input, rbytes));
+            state.pixels.extend(decoder.decode_row(&row[..nbytes], cols));
+            state.rows += 1;
+            left -= 1;
         }
-        Ok(table)
+        Ok(state)
     }
 
-    fn read_pixels<R: io::Read>(
-            input: &mut R, cols: usize, rows: usize, bpp: u16) -> Result<Pixels, LoadError> {
-        match bpp {
-            4 => Bitmap::read_pixels_4bpp(input, cols, rows),
-            _ => Err(LoadError::UnsupportedBpp),
-        }
+    /// Decode a bitmap as RGBA bytes in top-down order straight into `out`, which
+    /// must hold at least `4 * width * height` bytes, returning its dimensions.
+    /// The buffer size is checked before any pixel is decoded.
+    pub fn decode_into_rgba<R: io::Read>(input: &mut R, out: &mut [u8]) -> ::Result<(u32, u32)> {
+        Bitmap::decode_into_rgba_with_options(input, out, &DecodeOptions::default())
     }
 
-    fn read_pixels_4bpp<R: io::Read>(
-            input: &mut R, cols: usize, rows: usize) -> Result<Pixels, LoadError> {
-        let rbytes = ((4 * cols + 31) / 32) * 4;
-        let ebytes = rows * rbytes;
-        let buff = try!(Bitmap::read_section(input, ebytes));
-        let mut pixels = Pixels::new();
+    /// Decode a bitmap into `out` as `decode_into_rgba` does with the given decoding options
+    pub fn decode_into_rgba_with_options<R: io::Read>(
+            input: &mut R, out: &mut [u8], opts: &DecodeOptions) -> ::Result<(u32, u32)> {
+        let mut binput = io::BufReader::new(input);
+        let pre = try!(Bitmap::read_prelude(&mut binput, true, opts));
+        let (dib, ct) = (&pre.dib, &pre.colors);
+        let (cols, rows) = (dib.width as usize, dib.height as usize);
+        let needed = try!(cols.checked_mul(rows).and_then(|n| n.checked_mul(4))
+            .ok_or(LoadError::DimensionsTooLarge));
+        if out.len() < needed { return Err(LoadError::BufferTooSmall(needed, out.len())) }
 
+        let decoder = try!(Bitmap::row_decoder(dib));
+        let nbytes = try!(decoder.stride(cols).ok_or(LoadError::DimensionsTooLarge));
+        let (rbytes, _) = try!(Bitmap::aligned_section(nbytes, 1, opts.row_alignment));
+        // Without an alpha mask the fourth byte of 32 bpp pixels is reserved
+        let opaque = dib.bpp == 32 && dib.alpha_mask.map_or(true, |mask| mask == 0);
         for r in 0..rows {
-            for c in 0..cols {
-                let b = buff[r * rbytes + c / 2];
-                pixels.push((if c % 2 == 0 { b >> 4 } else { b & 0x0f }) as usize);
+            let row = try!(Bitmap::read_section(&mut binput, rbytes));
+            let y = if dib.top_down { r } else { rows - 1 - r };
+            let line = &mut out[4 * cols * y..4 * cols * (y + 1)];
+            for (x, p) in decoder.decode_row(&row[..nbytes], cols).into_iter().enumerate() {
+                let c = if dib.bpp <= 8 {
+                    ct.get(p).map_or(Rgbx(0, 0, 0, 0xff), |c| Rgbx(c.0, c.1, c.2, 0xff))
+                } else { Rgbx::from_pixel(p) };
+                let a = if opaque { 0xff } else { c.3 };
+                line[4 * x..4 * x + 4].copy_from_slice(&[c.0, c.1, c.2, a]);
             }
         }
-        Ok(pixels)
+        Ok((dib.width, dib.height))
     }
-}
 
-#[cfg(test)]
-mod test {
+    /// Read a bitmap keeping only its first `n` visual rows, as a preview. Top-down
+    /// images stop decoding after those rows, but bottom-up images store them last 
+    /// so they are decoded whole and then truncated.
+    pub fn read_top_rows<R: io::Read>(input: &mut R, n: u32) -> ::Result<Bitmap> {
+        let state = try!(Bitmap::read_partial(input, 0));
+        let height = state.prelude.dib.height;
+        let rows = ::std::cmp::min(n, height);
+        let needed = if state.prelude.dib.top_down { rows } else { height };
+        let mut state = try!(Bitmap::resume_partial(input, state, needed));
 
-    use std::io::Cursor;
+        let skip = (needed - rows) as usize * state.prelude.dib.width as usize;
+        state.pixels.drain(..skip);
+        state.rows = rows;
+        state.prelude.dib.height = rows;
+        let mut bmp = state.into_bitmap().expect("every kept row is decoded");
+        if rows != height { bmp.refresh_headers(); }
+        Ok(bmp)
+    }
 
-    use super::*;
+    /// Read the first image of a Windows cursor (.cur) file along with its hotspot
+    pub fn read_cur<R: io::Read + io::Seek>(
+            input: &mut R) -> ::Result<(Bitmap, (u16, u16))> {
+        Bitmap::read_icon(input, 2)
+    }
 
-    #[test]
-    #[should_fail(expected = "BadMagic")]
-    fn should_fail_read_bad_magic() {
-        let buff: Vec<u8> = vec![
-            0xcc, 0x0dd, // <-- 0xccdd is a bad magic
-            0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-        ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    /// Read every frame of an animated cursor, a RIFF container whose `fram` list 
+    /// has an `icon` chunk per frame holding an icon, a cursor or a BMP file. 
+    /// Other chunks are skipped.
+    pub fn read_frames<R: io::Read + io::Seek>(input: &mut R) -> ::Result<Vec<Bitmap>> {
+        let start = try!(input.seek(io::SeekFrom::Current(0)));
+        let riff = try!(Bitmap::read_section(input, 12));
+        if &riff[0..4] != b"RIFF" { return Err(LoadError::BadMagic)}
+        let end = start + 8 + dword!(riff, 4) as u64;
+
+        let mut frames = Vec::new();
+        let mut pos = start + 12;
+        while pos + 8 <= end {
+            try!(input.seek(io::SeekFrom::Start(pos)));
+            let chunk = try!(Bitmap::read_section(input, 8));
+            let size = dword!(chunk, 4) as u64;
+            if &chunk[0..4] == b"LIST" && size >= 4 && 
+                    try!(Bitmap::read_section(input, 4)) == b"fram" {
+                let list_end = pos + 8 + size;
+                let mut sub = pos + 12;
+                while sub + 8 <= list_end {
+                    try!(input.seek(io::SeekFrom::Start(sub)));
+                    let head = try!(Bitmap::read_section(input, 8));
+                    let n = dword!(head, 4) as u64;
+                    if &head[0..4] == b"icon" {
+                        let data = try!(Bitmap::read_section(input, n as usize));
+                        frames.push(try!(Bitmap::read_frame(&data)));
+                    }
+                    // Chunks are padded to an even size
+                    sub += 8 + n + n % 2;
+                }
+            }
+            pos += 8 + size + size % 2;
+        }
+        Ok(frames)
     }
 
-    #[test]
-    #[should_fail(expected = "UnexpectedEof")]
-    fn should_fail_read_unexpected_eof_in_header() {
-        let buff: Vec<u8> = vec![
-            0x42, 0x04d, 
-            0x00, 0x00, 0x00, 0x00,
-                                    // <-- more bytes expected
-        ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    /// Read a frame of an animated cursor, which may be a BMP, icon or cursor file
+    fn read_frame(data: &[u8]) -> Result<Bitmap, LoadError> {
+        if data.len() < 4 { return Err(LoadError::UnexpectedEof)}
+        if data[0] == 0x42 && data[1] == 0x4d { return Bitmap::read(&mut io::Cursor::new(data))}
+        let (bmp, _) = try!(Bitmap::read_icon(&mut io::Cursor::new(data), word!(data, 2)));
+        Ok(bmp)
     }
 
-    #[test]
-    #[should_fail(expected = "UnsupportedDib")]
-    fn should_fail_read_unsupported_dib() {
-        let buff: Vec<u8> = vec![
-            0x42, 0x04d, 
-            0x52, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-            0x46, 0x00, 0x00, 0x00,
+    /// Read the first image of an icon (`kind` 1) or cursor (`kind` 2) file along 
+    /// with the two words following its size, which are the hotspot of cursors.
+    fn read_icon<R: io::Read + io::Seek>(
+            input: &mut R, kind: u16) -> Result<(Bitmap, (u16, u16)), LoadError> {
+        let dir = try!(Bitmap::read_section(input, 6));
 
-            0xaa, 0xbb, 0xcc, 0xdd, // <-- 0xaabbccdd is a unknown size
+        // Reserved word must be 0, resource type must be the expected one
+        if word!(dir, 0) != 0 || word!(dir, 2) != kind || word!(dir, 4) == 0 { 
+            return Err(LoadError::BadMagic)
+        }
+
+        let entry = try!(Bitmap::read_section(input, 16));
+        let hotspot = (word!(entry, 4), word!(entry, 6));
+        try!(input.seek(io::SeekFrom::Start(dword!(entry, 12) as u64)));
+
+        // The image is a DIB with no file header whose height covers both the
+        // color (XOR) and the transparency (AND) masks
+        let mut pre = try!(Bitmap::read_prelude(input, false, &DecodeOptions::default()));
+        pre.dib.height /= 2;
+        pre.header = Bitmap::synthesize_header(&pre.dib, &pre.colors);
+        let mut pixels = Pixels::new();
+        try!(Bitmap::read_pixels(input, &pre.dib, 4, &mut pixels, &mut |_| {}));
+        Ok((pre.into_bitmap(pixels), hotspot))
+    }
+
+    /// Read a bitmap with no file header, whose data starts at the DIB as in the 
+    /// CF_DIB clipboard format.
+    pub fn read_dib_only<R: io::Read>(input: &mut R) -> ::Result<Bitmap> {
+        let mut binput = io::BufReader::new(input);
+        let pre = try!(Bitmap::read_prelude(&mut binput, false, &DecodeOptions::default()));
+        let mut pixels = Pixels::new();
+        try!(Bitmap::read_pixels(&mut binput, &pre.dib, 4, &mut pixels, &mut |_| {}));
+        Ok(pre.into_bitmap(pixels))
+    }
+
+    /// Read just the color table of a bitmap, stopping before its pixels
+    pub fn read_palette<R: io::Read>(input: &mut R) -> ::Result<ColorTable> {
+        let pre = try!(Bitmap::read_prelude(input, true, &DecodeOptions::default()));
+        Ok(pre.colors)
+    }
+
+    /// Read and validate everything preceding the pixels of a bitmap, leaving the
+    /// input at its first pixel. Without a file header, as in icons and CF_DIB data, 
+    /// the pixels follow the color table and a header is synthesized for them.
+    fn read_prelude<R: io::Read>(
+            input: &mut R, file_header: bool, opts: &DecodeOptions) -> Result<Prelude, LoadError> {
+        let hd = if file_header { 
+            Some(try!(Bitmap::read_header(input, opts.byte_order))) 
+        } else { None };
+        let (mut dib, raw_dib) = try!(Bitmap::read_dib(input, opts.byte_order));
+        try!(Bitmap::check_compression(&dib));
+        let mut ncolors = Bitmap::table_len(&dib);
+        if opts.lenient { 
+            if let Some(ref hd) = hd { ncolors = Bitmap::infer_colors(hd, &mut dib, ncolors); }
+            Bitmap::infer_planes(&mut dib);
+        }
+        try!(Bitmap::check_planes(&dib));
+        if let Some(ref hd) = hd { try!(Bitmap::check_layout(hd, &dib, ncolors)); }
+        if opts.strict { try!(Bitmap::check_size(&dib, opts.row_alignment)); }
+        // The color table always follows the DIB, wherever the pixels start
+        let ct = try!(Bitmap::read_dib_color_table(input, &dib, ncolors));
+        try!(Bitmap::check_palette(&dib, &ct));
+
+        let hd = match hd {
+            Some(hd) => {
+                // The layout check guarantees the offset is not before the table end
+                let consumed = Bitmap::table_end(&dib, ct.len());
+                try!(Bitmap::skip_bytes(input, hd.offset as u64 - consumed));
+                hd
+            },
+            None => Bitmap::synthesize_header(&dib, &ct),
+        };
+        Ok(Prelude { header: hd, dib: dib, raw_dib: raw_dib, colors: ct })
+    }
+
+    /// The offset in a file where a color table of `ncolors` entries following 
+    /// the given DIB ends
+    fn table_end(dib: &Dib, ncolors: usize) -> u64 {
+        match dib.version {
+            HeaderVersion::Core => 14 + 12 + 3 * ncolors as u64,
+            _ => 14 + 40 + dib.extra.len() as u64 + 4 * ncolors as u64,
+        }
+    }
+
+    /// The file header of a bitmap whose pixels follow its color table
+    fn synthesize_header(dib: &Dib, ct: &ColorTable) -> Header {
+        let offset = Bitmap::table_end(dib, ct.len()) as u32;
+        let size = offset.saturating_add(Bitmap::image_size(dib));
+        Header { size: size, reserved: 0, offset: offset }
+    }
+
+    /// The size of the pixel data, computed from the dimensions when the DIB leaves
+    /// it as 0, which uncompressed images are allowed to do.
+    fn image_size(dib: &Dib) -> u32 {
+        if dib.size != 0 { return dib.size }
+        (Bitmap::stride(dib.width as usize, dib.bpp) * dib.height as usize) as u32
+    }
+
+    /// Read a bitmap calling `on_progress` with the fraction of pixel rows decoded
+    /// so far, which reaches exactly 1.0 when the decode completes.
+    pub fn read_with_progress<R: io::Read, F: FnMut(f32)>(
+            input: &mut R, mut on_progress: F) -> ::Result<Bitmap> {
+        let mut pixels = Pixels::new();
+        let pre = try!(Bitmap::read_parts(
+            input, &mut pixels, &DecodeOptions::default(), &mut on_progress));
+        Ok(pre.into_bitmap(pixels))
+    }
+
+    fn read_parts<R: io::Read>(
+            input: &mut R, 
+            pixels: &mut Pixels,
+            opts: &DecodeOptions,
+            progress: &mut FnMut(f32)) -> Result<Prelude, LoadError> {
+        let mut binput = io::BufReader::new(input);
+        let pre = try!(Bitmap::read_prelude(&mut binput, true, opts));
+        pixels.clear();
+        try!(Bitmap::read_pixels(&mut binput, &pre.dib, opts.row_alignment, pixels, progress));
+        Ok(pre)
+    }
+
+    /// Resolve the color of a pixel value, looking it up in the color table for
+    /// indexed images. The fourth component of the result is the alpha channel,
+    /// which is opaque for images that carry no alpha information.
+    fn resolve(&self, p: usize) -> Option<Rgbx> {
+        if self.is_indexed() { 
+            self.colors.get(p).map(|c| Rgbx(c.0, c.1, c.2, 0xff))
+        } else { 
+            Some(Rgbx::from_pixel(p)) 
+        }
+    }
+
+    /// Resolve the color at visual position `(x, y)`. Pixels that cannot be
+    /// resolved are opaque black.
+    fn color(&self, x: usize, y: usize) -> Rgbx {
+        self.resolve(self.row(y)[x]).unwrap_or(Rgbx(0, 0, 0, 0xff))
+    }
+
+    fn is_indexed(&self) -> bool {
+        self.dib.bpp <= 8
+    }
+
+    /// Extract the raw fourth byte of each pixel of 32 bpp images. In BI_RGB files
+    /// that byte is reserved, so pixels are made opaque unless there is an alpha mask.
+    fn split_alpha(pixels: &mut Pixels, dib: &Dib) -> Option<Vec<u8>> {
+        if dib.bpp != 32 { return None }
+        let raw = pixels.iter().map(|&p| p as u8).collect();
+        match dib.alpha_mask {
+            Some(mask) if mask != 0 => {},
+            _ => for p in pixels.iter_mut() { *p |= 0xff; },
+        }
+        Some(raw)
+    }
+
+    /// Obtain the bytes of visual row `y` (being 0 the top row) encoded at the given bpp.
+    /// The result is padded to a 4-byte boundary as it would be stored in a BMP file. 
+    pub fn scanline_bytes(&self, y: u32, bpp: u16) -> Result<Vec<u8>, ScanlineError> {
+        if y >= self.dib.height { return Err(ScanlineError::RowOutOfRange)}
+        self.encode_row(self.row(y as usize), bpp)
+    }
+
+    /// Write a thumbnail of this bitmap as a 24 bpp BMP. The image is downscaled
+    /// (nearest-neighbor) so its longest side is at most `max_dim` pixels.
+    pub fn write_thumbnail<W: io::Write>(&self, out: &mut W, max_dim: u32) -> io::Result<()> {
+        let (w, h) = (self.dib.width, self.dib.height);
+        let longest = if w > h { w } else { h };
+        if longest <= max_dim { return self.write(out, 24) }
+
+        let tw = (w as u64 * max_dim as u64 / longest as u64) as u32;
+        let th = (h as u64 * max_dim as u64 / longest as u64) as u32;
+        let thumb = self.resize_nearest(if tw == 0 { 1 } else { tw }, if th == 0 { 1 } else { th });
+        thumb.write(out, 24)
+    }
+
+    /// Crop the border of pixels with value `border_index` around the image. 
+    /// If the whole image is border, the result is a 1x1 image of that pixel.
+    pub fn trim(&self, border_index: usize) -> Bitmap {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        let (mut left, mut top, mut right, mut bottom) = (w, h, 0, 0);
+        for y in 0..h {
+            for (x, &p) in self.row(y).iter().enumerate() {
+                if p == border_index { continue }
+                if x < left { left = x; }
+                if x + 1 > right { right = x + 1; }
+                if y < top { top = y; }
+                if y + 1 > bottom { bottom = y + 1; }
+            }
+        }
+
+        if left >= right { return self.generate(1, 1, |_, _| border_index) }
+        if left == 0 && top == 0 && right == w && bottom == h { return self.clone() }
+        self.generate((right - left) as u32, (bottom - top) as u32, |x, y| 
+            self.row(top + y)[left + x])
+    }
+
+    /// Obtain a mask telling, for each pixel in top-down order, whether its luminance 
+    /// exceeds `level`.
+    pub fn threshold(&self, level: u8) -> Vec<bool> {
+        let mut mask = Vec::with_capacity(self.pixels.len());
+        for y in 0..self.dib.height as usize {
+            for &p in self.row(y) {
+                mask.push(self.resolve(p).map_or(false, |c| c.luminance() > level));
+            }
+        }
+        mask
+    }
+
+    /// Negate the colors of the image, leaving the alpha channel untouched. 
+    /// Indexed images have their color table inverted instead of their pixels.
+    pub fn invert(&mut self) {
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() {
+                *c = Rgbx(255 - c.0, 255 - c.1, 255 - c.2, c.3);
+            }
+        } else {
+            for p in self.pixels.iter_mut() {
+                let c = Rgbx::from_pixel(*p);
+                *p = Rgbx(255 - c.0, 255 - c.1, 255 - c.2, c.3).to_pixel();
+            }
+        }
+    }
+
+    /// Build a double-height image alternating the rows of this image and `other`,
+    /// starting with the top row of this one.
+    pub fn interleave_rows(&self, other: &Bitmap) -> Result<Bitmap, InterleaveError> {
+        if self.dib.width != other.dib.width { return Err(InterleaveError::WidthMismatch)}
+        if self.dib.height != other.dib.height { return Err(InterleaveError::HeightMismatch)}
+        if self.dib.bpp != other.dib.bpp || self.colors != other.colors { 
+            return Err(InterleaveError::PaletteMismatch)
+        }
+        Ok(self.generate(self.dib.width, 2 * self.dib.height, |x, y| 
+            if y % 2 == 0 { self.row(y / 2)[x] } else { other.row(y / 2)[x] }))
+    }
+
+    /// Join `right` to the right side of this image. Images with different pixel
+    /// formats or color tables are both converted to direct color.
+    pub fn concat_horizontal(&self, right: &Bitmap) -> Result<Bitmap, ConcatError> {
+        if self.dib.height != right.dib.height { return Err(ConcatError::HeightMismatch)}
+        let (left, right) = self.compatible_pair(right);
+        let w = left.dib.width as usize;
+        Ok(left.generate(left.dib.width + right.dib.width, left.dib.height, |x, y| 
+            if x < w { left.row(y)[x] } else { right.row(y)[x - w] }))
+    }
+
+    /// Stack `bottom` under this image. Images with different pixel formats or 
+    /// color tables are both converted to direct color.
+    pub fn concat_vertical(&self, bottom: &Bitmap) -> Result<Bitmap, ConcatError> {
+        if self.dib.width != bottom.dib.width { return Err(ConcatError::WidthMismatch)}
+        let (top, bottom) = self.compatible_pair(bottom);
+        let h = top.dib.height as usize;
+        Ok(top.generate(top.dib.width, top.dib.height + bottom.dib.height, |x, y| 
+            if y < h { top.row(y)[x] } else { bottom.row(y - h)[x] }))
+    }
+
+    /// Obtain copies of this image and `other` sharing the same pixel format.
+    fn compatible_pair(&self, other: &Bitmap) -> (Bitmap, Bitmap) {
+        let compatible = if self.is_indexed() { 
+            other.dib.bpp == self.dib.bpp && other.colors == self.colors 
+        } else { 
+            !other.is_indexed() 
+        };
+        if compatible { (self.clone(), other.clone()) }
+        else { (self.to_direct(), other.to_direct()) }
+    }
+
+    /// Convert this image to 32 bpp direct color, resolving the indexed pixels.
+    fn to_direct(&self) -> Bitmap {
+        let mut bmp = self.clone();
+        if !self.is_indexed() { return bmp }
+
+        bmp.pixels = self.pixels.iter()
+            .map(|&p| self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff)).to_pixel())
+            .collect();
+        bmp.colors = ColorTable::new();
+        bmp.dib.bpp = 32;
+        bmp.dib.imp_colors = 0;
+        bmp.refresh_headers();
+        bmp.raw_alpha = None;
+        bmp
+    }
+
+    /// Describe the current format and dimensions with a fresh BITMAPINFOHEADER
+    /// followed by the color table, dropping the headers as read from a file, 
+    /// which no longer match the pixels.
+    fn refresh_headers(&mut self) {
+        self.dib.comp = 0;
+        self.dib.size = (Bitmap::stride(self.dib.width as usize, self.dib.bpp) * 
+                         self.dib.height as usize) as u32;
+        self.dib.colors = self.colors.len() as u32;
+        self.dib.rgb_masks = None;
+        self.dib.alpha_mask = None;
+        self.dib.gamma = None;
+        self.dib.intent = None;
+        self.dib.extra = Vec::new();
+        self.dib.version = HeaderVersion::Info;
+        self.header.offset = 14 + 40 + 4 * self.colors.len() as u32;
+        self.header.size = self.header.offset + self.dib.size;
+        self.raw_dib = Vec::new();
+    }
+
+    /// Obtain the index of the color table entry nearest to `color` in RGB space.
+    /// The first entry wins ties, and 0 is returned for an empty table.
+    pub fn closest_index(&self, color: Rgbx) -> usize {
+        Bitmap::nearest(&self.colors, &color)
+    }
+
+    /// Iterate over the resolved pixel colors along with their `(x, y)` coordinates,
+    /// in top-down row-major order.
+    pub fn enumerate_pixels<'a>(&'a self) -> impl Iterator<Item = (u32, u32, Rgbx)> + 'a {
+        let width = self.dib.width;
+        (0..self.dib.height).flat_map(move |y| (0..width).map(move |x| 
+            (x, y, self.color(x as usize, y as usize))))
+    }
+
+    /// Reduce the image to at most `max_colors` colors, remapping every pixel to 
+    /// the nearest surviving one. The important entries of the color table are
+    /// kept first, and then the most used colors. Direct-color images become 8 bpp.
+    /// `max_colors` is clamped to 1..=256, the range an 8 bpp color table can hold.
+    pub fn quantize(&self, max_colors: usize) -> Bitmap {
+        self.quantize_with_options(max_colors, &QuantizeOptions::default())
+    }
+
+    /// Reduce the image to at most `max_colors` colors as `quantize` does, with 
+    /// the given options.
+    pub fn quantize_with_options(&self, max_colors: usize, opts: &QuantizeOptions) -> Bitmap {
+        let max_colors = ::std::cmp::max(1, ::std::cmp::min(max_colors, 256));
+        let mut usage = HashMap::new();
+        for &p in self.pixels.iter() {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            *usage.entry(Rgbx(c.0, c.1, c.2, 0).to_pixel()).or_insert(0) += 1;
+        }
+
+        let mut palette = ColorTable::new();
+        let important = if self.is_indexed() { self.dib.important_colors() as usize } else { 0 };
+        for c in self.colors.iter().take(important) {
+            let c = Rgbx(c.0, c.1, c.2, 0);
+            if palette.len() < max_colors && !palette.contains(&c) { palette.push(c); }
+        }
+
+        let mut popular: Vec<(usize, usize)> = usage.into_iter().collect();
+        popular.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (p, _) in popular {
+            let c = Rgbx::from_pixel(p);
+            if palette.len() < max_colors && !palette.contains(&c) { palette.push(c); }
+        }
+
+        let pixels = if opts.dither { self.dither(&palette) } else {
+            let mut remap = HashMap::new();
+            self.pixels.iter().map(|&p| *remap.entry(p).or_insert_with(|| {
+                let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+                Bitmap::nearest(&palette, &c)
+            })).collect()
+        };
+
+        let bpp = if self.is_indexed() { self.dib.bpp } else { 8 };
+        let mut bmp = self.with_palette(palette, pixels, bpp);
+        bmp.dib.imp_colors = if important < bmp.colors.len() { important as u32 } else { 0 };
+        bmp
+    }
+
+    /// Remap every pixel to the nearest entry of `palette` with Floyd-Steinberg error
+    /// diffusion. Pixels are visited top-down and left to right, so the result is
+    /// deterministic.
+    fn dither(&self, palette: &ColorTable) -> Pixels {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        let clamp = |v: i32| if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 };
+        let mut pixels = vec![0; w * h];
+
+        // Errors are kept in sixteenths, with a spare entry at both ends of a row
+        let mut errors = vec![[0i32; 3]; w + 2];
+        for y in 0..h {
+            let mut next = vec![[0i32; 3]; w + 2];
+            for x in 0..w {
+                let c = self.color(x, y);
+                let e = errors[x + 1];
+                let want = Rgbx(clamp(c.0 as i32 + e[0] / 16), clamp(c.1 as i32 + e[1] / 16),
+                                clamp(c.2 as i32 + e[2] / 16), 0);
+                let i = Bitmap::nearest(palette, &want);
+                let got = palette.get(i).unwrap_or(&want);
+                let diff = [want.0 as i32 - got.0 as i32, want.1 as i32 - got.1 as i32, 
+                            want.2 as i32 - got.2 as i32];
+                for k in 0..3 {
+                    errors[x + 2][k] += 7 * diff[k];
+                    next[x][k] += 3 * diff[k];
+                    next[x + 1][k] += 5 * diff[k];
+                    next[x + 2][k] += diff[k];
+                }
+                pixels[self.index_of(x, y)] = i;
+            }
+            errors = next;
+        }
+        pixels
+    }
+
+    /// Remap every pixel to the nearest entry of a fixed palette of up to 256 colors,
+    /// so several images can share it. The result is 1, 4 or 8 bpp as the palette needs.
+    /// Entries past the first 256 are ignored, and an empty palette leaves the image
+    /// unchanged as there is nothing to map onto.
+    pub fn to_indexed_with_palette(&self, palette: &ColorTable) -> Bitmap {
+        if palette.is_empty() { return self.clone() }
+        let palette: &ColorTable = &palette.iter().take(256).cloned().collect();
+        let mut remap = HashMap::new();
+        let pixels = self.pixels.iter().map(|&p| *remap.entry(p).or_insert_with(|| {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            Bitmap::nearest(palette, &c)
+        })).collect();
+
+        let bpp = match palette.len() { 0...2 => 1, 3...16 => 4, _ => 8 };
+        self.with_palette(palette.clone(), pixels, bpp)
+    }
+
+    /// The index of the entry of `palette` nearest to `color`, 0 if it is empty.
+    fn nearest(palette: &ColorTable, color: &Rgbx) -> usize {
+        let mut best = 0;
+        for (i, c) in palette.iter().enumerate() {
+            if c.distance(color) < palette[best].distance(color) { best = i; }
+        }
+        best
+    }
+
+    /// Build a copy of this image with a new color table and pixels at the given bpp.
+    fn with_palette(&self, palette: ColorTable, pixels: Pixels, bpp: u16) -> Bitmap {
+        let mut bmp = self.clone();
+        bmp.dib.bpp = bpp;
+        bmp.dib.imp_colors = 0;
+        bmp.colors = palette;
+        bmp.pixels = pixels;
+        bmp.refresh_headers();
+        bmp.raw_alpha = None;
+        bmp
+    }
+
+    /// Extend the canvas to `w`x`h` pixels filled with `background`, centering the image.
+    pub fn pad_to(&self, w: u32, h: u32, background: usize) -> Result<Bitmap, PadError> {
+        self.pad_to_anchored(w, h, background, Anchor::Center)
+    }
+
+    /// Extend the canvas to `w`x`h` pixels filled with `background`, placing the 
+    /// image according to `anchor`.
+    pub fn pad_to_anchored(&self, w: u32, h: u32, background: usize, 
+                           anchor: Anchor) -> Result<Bitmap, PadError> {
+        let (sw, sh) = (self.dib.width, self.dib.height);
+        if w < sw || h < sh { return Err(PadError::TargetTooSmall)}
+        let (left, top) = match anchor {
+            Anchor::Center => (((w - sw) / 2) as usize, ((h - sh) / 2) as usize),
+            Anchor::TopLeft => (0, 0),
+        };
+        let (sw, sh) = (sw as usize, sh as usize);
+        Ok(self.generate(w, h, |x, y| 
+            if x >= left && x < left + sw && y >= top && y < top + sh { 
+                self.row(y - top)[x - left] 
+            } else { 
+                background 
+            }))
+    }
+
+    /// Extend the canvas by one column on the right and one row at the bottom, filled
+    /// with `pad_index`, where the width and height respectively are odd.
+    pub fn ensure_even_dimensions(&self, pad_index: usize) -> Bitmap {
+        let (w, h) = (self.dib.width, self.dib.height);
+        if w % 2 == 0 && h % 2 == 0 { return self.clone() }
+        let (sw, sh) = (w as usize, h as usize);
+        self.generate(w + w % 2, h + h % 2, |x, y| 
+            if x < sw && y < sh { self.row(y)[x] } else { pad_index })
+    }
+
+    /// Hash the resolved RGB bytes of every visual row, top-down, so changed rows 
+    /// can be spotted by comparing the hashes of two versions of an image.
+    /// The hash is FNV-1a, hence stable across runs and platforms.
+    pub fn row_hashes(&self) -> Vec<u64> {
+        (0..self.dib.height as usize).map(|y| {
+            let mut hash = 0xcbf29ce484222325u64;
+            for x in 0..self.dib.width as usize {
+                let c = self.color(x, y);
+                for &byte in [c.0, c.1, c.2].iter() {
+                    hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+                }
+            }
+            hash
+        }).collect()
+    }
+
+    /// Obtain the resolved pixels as normalized RGBA floats in top-down order. 
+    /// Alpha is 1.0 for images without alpha channel.
+    pub fn to_rgba_f32(&self) -> Vec<f32> {
+        let mut out = Vec::with_capacity(4 * self.pixels.len());
+        for (_, _, c) in self.enumerate_pixels() {
+            out.extend([c.0, c.1, c.2, c.3].iter().map(|&v| v as f32 / 255.0));
+        }
+        out
+    }
+
+    /// Obtain the resolved color of every pixel in top-down, row-major order, so
+    /// the color at `(x, y)` is at index `y * width + x`.
+    pub fn to_color_grid(&self) -> Vec<Rgbx> {
+        self.enumerate_pixels().map(|(_, _, c)| c).collect()
+    }
+
+    /// Rotate the `len` color table entries starting at `start` by `by` positions,
+    /// so the entry at `i` moves to `i + by` wrapping within the range. Pixels
+    /// are left untouched, which animates the image for color cycling effects.
+    pub fn cycle_palette(&mut self, start: usize, len: usize, by: i32) -> Result<(), PaletteError> {
+        let end = try!(start.checked_add(len).ok_or(PaletteError::RangeOutOfBounds));
+        if end > self.colors.len() { return Err(PaletteError::RangeOutOfBounds)}
+        if len == 0 { return Ok(()) }
+
+        let shift = (by as i64).rem_euclid(len as i64) as usize;
+        self.colors[start..end].rotate_right(shift);
+        Ok(())
+    }
+
+    /// Copy the `(x, y, width, height)` region of `src` into this image at `dst`.
+    /// The region is clipped to the bounds of both images.
+    pub fn blit(&mut self, src: &Bitmap, src_rect: (u32, u32, u32, u32), 
+                dst: (u32, u32)) -> Result<(), BlitError> {
+        let compatible = if self.is_indexed() { 
+            src.dib.bpp == self.dib.bpp && src.colors == self.colors 
+        } else { 
+            !src.is_indexed() 
+        };
+        if !compatible { return Err(BlitError::FormatMismatch)}
+
+        let (sx, sy, w, h) = src_rect;
+        let (dx, dy) = dst;
+        let clip = |from: u32, to: u32, len: u32, src_len: u32, dst_len: u32| {
+            let src_room = src_len.saturating_sub(from);
+            let dst_room = dst_len.saturating_sub(to);
+            if len < src_room && len < dst_room { len }
+            else if src_room < dst_room { src_room } 
+            else { dst_room }
+        };
+        let w = clip(sx, dx, w, src.dib.width, self.dib.width) as usize;
+        let h = clip(sy, dy, h, src.dib.height, self.dib.height) as usize;
+
+        for y in 0..h {
+            for x in 0..w {
+                let p = src.row(sy as usize + y)[sx as usize + x];
+                let i = self.index_of(dx as usize + x, dy as usize + y);
+                self.pixels[i] = p;
+            }
+        }
+        Ok(())
+    }
+
+    /// The horizontal and vertical resolution in dots per inch, being 
+    /// `DEFAULT_DPI` the resolution of the axes with no pixels per meter set.
+    pub fn dpi(&self) -> (u32, u32) {
+        let dpi = |ppm: u32| if ppm == 0 { DEFAULT_DPI } 
+                             else { ((ppm as f64 * 0.0254).round()) as u32 };
+        (dpi(self.dib.ppm_x), dpi(self.dib.ppm_y))
+    }
+
+    /// The red, green and blue gammas declared by the header, if any
+    pub fn gamma(&self) -> Option<(f64, f64, f64)> {
+        self.dib.gamma
+    }
+
+    /// Resize the image averaging the source pixels covered by each destination
+    /// pixel (box filter). The result is a direct-color image, which is blank when
+    /// the source has no pixels.
+    pub fn resize_box(&self, new_w: u32, new_h: u32) -> Bitmap {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        let (nw, nh) = (new_w as usize, new_h as usize);
+        if w == 0 || h == 0 { return self.to_direct().generate(new_w, new_h, |_, _| 0) }
+        let span = |i: usize, from: usize, to: usize| {
+            let start = i * from / to;
+            let end = (i + 1) * from / to;
+            (start, if end > start { end } else { start + 1 })
+        };
+        self.to_direct().generate(new_w, new_h, |x, y| {
+            let (x0, x1) = span(x, w, nw);
+            let (y0, y1) = span(y, h, nh);
+            let mut sum = [0usize; 4];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let c = self.color(sx, sy);
+                    sum[0] += c.0 as usize;
+                    sum[1] += c.1 as usize;
+                    sum[2] += c.2 as usize;
+                    sum[3] += c.3 as usize;
+                }
+            }
+            let n = (x1 - x0) * (y1 - y0);
+            Rgbx((sum[0] / n) as u8, (sum[1] / n) as u8, 
+                 (sum[2] / n) as u8, (sum[3] / n) as u8).to_pixel()
+        })
+    }
+
+    /// Obtain the values of one channel of the resolved pixels in top-down order.
+    pub fn extract_channel(&self, ch: Channel) -> Vec<u8> {
+        self.enumerate_pixels().map(|(_, _, c)| c.channel(ch)).collect()
+    }
+
+    /// Replace every color whose red, green and blue are within `tolerance` of 
+    /// those of `from` with `to`. Indexed images have their color table edited.
+    pub fn replace_color(&mut self, from: Rgbx, to: Rgbx, tolerance: u8) {
+        let near = |c: &Rgbx| {
+            let d = |a: u8, b: u8| if a > b { a - b } else { b - a };
+            d(c.0, from.0) <= tolerance && d(c.1, from.1) <= tolerance && 
+                d(c.2, from.2) <= tolerance
+        };
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() {
+                if near(c) { *c = to.clone(); }
+            }
+        } else {
+            for p in self.pixels.iter_mut() {
+                if near(&Rgbx::from_pixel(*p)) { *p = to.to_pixel(); }
+            }
+        }
+    }
+
+    /// Set to `index` the 4-connected region of pixels sharing the value of the 
+    /// pixel at visual position `(x, y)`. Seeds out of the image are ignored.
+    pub fn flood_fill(&mut self, x: u32, y: u32, index: usize) {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        if x as usize >= w || y as usize >= h { return }
+        let target = self.pixels[self.index_of(x as usize, y as usize)];
+        if target == index { return }
+
+        // An explicit stack, so large regions cannot overflow the call stack
+        let mut pending = vec![(x as usize, y as usize)];
+        while let Some((x, y)) = pending.pop() {
+            let i = self.index_of(x, y);
+            if self.pixels[i] != target { continue }
+            self.pixels[i] = index;
+            if x > 0 { pending.push((x - 1, y)); }
+            if x + 1 < w { pending.push((x + 1, y)); }
+            if y > 0 { pending.push((x, y - 1)); }
+            if y + 1 < h { pending.push((x, y + 1)); }
+        }
+    }
+
+    /// The color table packed as 3 or 4 bytes per entry in the given order. The 
+    /// alpha of color table entries is always opaque.
+    pub fn palette_bytes(&self, order: ChannelOrder) -> Vec<u8> {
+        let mut buff = Vec::new();
+        for c in self.colors.iter() {
+            match order {
+                ChannelOrder::Rgb => buff.extend([c.0, c.1, c.2].iter().cloned()),
+                ChannelOrder::Bgr => buff.extend([c.2, c.1, c.0].iter().cloned()),
+                ChannelOrder::Rgba => buff.extend([c.0, c.1, c.2, 0xff].iter().cloned()),
+                ChannelOrder::Bgra => buff.extend([c.2, c.1, c.0, 0xff].iter().cloned()),
+            }
+        }
+        buff
+    }
+
+    /// The mean color of all pixels, where the color channels are weighted by alpha
+    /// so transparent pixels do not count. Fully transparent images are transparent black.
+    pub fn average_color(&self) -> Rgbx {
+        let (mut r, mut g, mut b, mut a) = (0u64, 0u64, 0u64, 0u64);
+        for &p in self.pixels.iter() {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            r += c.0 as u64 * c.3 as u64;
+            g += c.1 as u64 * c.3 as u64;
+            b += c.2 as u64 * c.3 as u64;
+            a += c.3 as u64;
+        }
+        if a == 0 { return Rgbx(0, 0, 0, 0) }
+        let mean = |sum: u64, n: u64| ((sum + n / 2) / n) as u8;
+        Rgbx(mean(r, a), mean(g, a), mean(b, a), mean(a, self.pixels.len() as u64))
+    }
+
+    /// Snap the red, green and blue of every color to `levels` evenly spaced values,
+    /// editing the color table of indexed images. Fewer than 2 levels count as 2.
+    pub fn posterize(&mut self, levels: u8) {
+        let steps = if levels < 2 { 1 } else { levels as u32 - 1 };
+        let snap = |v: u8| ((v as u32 * steps + 127) / 255 * 255 / steps) as u8;
+        let apply = |c: &Rgbx| Rgbx(snap(c.0), snap(c.1), snap(c.2), c.3);
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() { *c = apply(c); }
+        } else {
+            for p in self.pixels.iter_mut() { *p = apply(&Rgbx::from_pixel(*p)).to_pixel(); }
+        }
+    }
+
+    /// Replace the color of every pixel with the result of `f` on its visual position
+    /// `(x, y)` and its color. Indexed images are converted to direct color first.
+    pub fn map_pixels<F: Fn(u32, u32, Rgbx) -> Rgbx>(&mut self, f: F) {
+        if self.is_indexed() { *self = self.to_direct(); }
+        for y in 0..self.dib.height {
+            for x in 0..self.dib.width {
+                let c = self.color(x as usize, y as usize);
+                let i = self.index_of(x as usize, y as usize);
+                self.pixels[i] = f(x, y, c).to_pixel();
+            }
+        }
+    }
+
+    /// Adjust the red, green and blue of every color as `(c - 128) * contrast + 128 
+    /// + brightness`, saturating at 0 and 255. Indexed images have their color 
+    /// table adjusted.
+    pub fn adjust(&mut self, brightness: i16, contrast: f32) {
+        let level = |v: u8| {
+            let n = (v as f32 - 128.0) * contrast + 128.0 + brightness as f32;
+            if n < 0.0 { 0 } else if n > 255.0 { 255 } else { n.round() as u8 }
+        };
+        let apply = |c: &Rgbx| Rgbx(level(c.0), level(c.1), level(c.2), c.3);
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() { *c = apply(c); }
+        } else {
+            for p in self.pixels.iter_mut() { *p = apply(&Rgbx::from_pixel(*p)).to_pixel(); }
+        }
+    }
+
+    /// Summarize this image in a single pass over its pixels. Empty images have 
+    /// all luminances 0.
+    pub fn stats(&self) -> ImageStats {
+        let mut colors = HashMap::new();
+        let (mut min, mut max, mut sum) = (0xff, 0, 0u64);
+        let mut transparent = false;
+        for &p in self.pixels.iter() {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            let l = c.luminance();
+            if l < min { min = l; }
+            if l > max { max = l; }
+            sum += l as u64;
+            transparent = transparent || (self.dib.bpp >= 32 && c.3 < 0xff);
+            colors.insert(c.to_pixel(), ());
+        }
+        let n = self.pixels.len();
+        ImageStats {
+            width: self.dib.width,
+            height: self.dib.height,
+            bpp: self.dib.bpp,
+            distinct_colors: colors.len(),
+            min_luminance: if n == 0 { 0 } else { min },
+            max_luminance: max,
+            mean_luminance: if n == 0 { 0.0 } else { sum as f64 / n as f64 },
+            has_transparency: transparent,
+        }
+    }
+
+    /// Count the pixels of each luminance value
+    pub fn luminance_histogram(&self) -> [u32; 256] {
+        let mut bins = [0u32; 256];
+        for &p in self.pixels.iter() {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            bins[c.luminance() as usize] += 1;
+        }
+        bins
+    }
+
+    /// Spread the luminances of this image over the full range so they are evenly
+    /// distributed, scaling the red, green and blue of each color by the same factor
+    /// to keep its hue. Indexed images have their color table adjusted.
+    pub fn equalize(&mut self) {
+        let bins = self.luminance_histogram();
+        let mut cdf = [0u32; 256];
+        let mut total = 0;
+        for (l, &n) in bins.iter().enumerate() {
+            total += n;
+            cdf[l] = total;
+        }
+        let low = bins.iter().position(|&n| n > 0).map_or(0, |l| cdf[l]);
+        if total == low { return }
+
+        // Unused color table entries may be darker than any pixel, they go to black
+        let span = (total - low) as u64;
+        let target = |l: u8| 
+            ((cdf[l as usize].saturating_sub(low) as u64 * 255 + span / 2) / span) as u32;
+        let apply = |c: &Rgbx| {
+            let (from, to) = (c.luminance() as u32, target(c.luminance()));
+            if from == 0 { return Rgbx(to as u8, to as u8, to as u8, c.3) }
+            let scale = |v: u8| ::std::cmp::min(255, (v as u32 * to + from / 2) / from) as u8;
+            Rgbx(scale(c.0), scale(c.1), scale(c.2), c.3)
+        };
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() { *c = apply(c); }
+        } else {
+            for p in self.pixels.iter_mut() { *p = apply(&Rgbx::from_pixel(*p)).to_pixel(); }
+        }
+    }
+
+    /// Permute the color channels, taking the new red, green and blue from the 
+    /// channels in `order`. Indexed images have their color table permuted.
+    pub fn swap_channels(&mut self, order: [Channel; 3]) {
+        let apply = |c: &Rgbx| 
+            Rgbx(c.channel(order[0]), c.channel(order[1]), c.channel(order[2]), c.3);
+        if self.is_indexed() {
+            for c in self.colors.iter_mut() { *c = apply(c); }
+        } else {
+            for p in self.pixels.iter_mut() { *p = apply(&Rgbx::from_pixel(*p)).to_pixel(); }
+        }
+    }
+
+    /// Clamp every pixel index to the last entry of the color table, so all pixels
+    /// can be resolved. Direct color images and empty color tables are left as they are.
+    pub fn clamp_to_palette(&mut self) {
+        if !self.is_indexed() || self.colors.is_empty() { return }
+        let last = self.colors.len() - 1;
+        for p in self.pixels.iter_mut() {
+            if *p > last { *p = last; }
+        }
+    }
+
+    /// Give fully transparent pixels of 32 bpp images the mean color of their 
+    /// neighbors that are not, spreading outwards until every transparent pixel 
+    /// connected to a visible one is colored. Alpha is left untouched.
+    pub fn alpha_bleed(&mut self) {
+        if self.dib.bpp != 32 { return }
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        let mut known: Vec<bool> = self.pixels.iter()
+            .map(|&p| Rgbx::from_pixel(p).3 != 0)
+            .collect();
+        loop {
+            let mut updates = Vec::new();
+            for r in 0..h {
+                for c in 0..w {
+                    if known[r * w + c] { continue }
+                    let (mut sum, mut n) = ([0u32; 3], 0);
+                    for nr in r.saturating_sub(1)..if r + 1 < h { r + 2 } else { h } {
+                        for nc in c.saturating_sub(1)..if c + 1 < w { c + 2 } else { w } {
+                            if !known[nr * w + nc] { continue }
+                            let color = Rgbx::from_pixel(self.pixels[nr * w + nc]);
+                            sum[0] += color.0 as u32;
+                            sum[1] += color.1 as u32;
+                            sum[2] += color.2 as u32;
+                            n += 1;
+                        }
+                    }
+                    if n > 0 { 
+                        let mean = |k: usize| (sum[k] / n) as u8;
+                        updates.push((r * w + c, Rgbx(mean(0), mean(1), mean(2), 0)));
+                    }
+                }
+            }
+            if updates.is_empty() { break }
+            for (i, color) in updates {
+                self.pixels[i] = color.to_pixel();
+                known[i] = true;
+            }
+        }
+    }
+
+    /// A 24 bpp copy of this image if it is 32 bpp and fully opaque, so its alpha
+    /// carries no information, or an unchanged copy otherwise.
+    pub fn drop_redundant_alpha(&self) -> Bitmap {
+        let mut bmp = self.clone();
+        if self.dib.bpp != 32 || self.has_transparency() { return bmp }
+        bmp.dib.bpp = 24;
+        bmp.refresh_headers();
+        bmp.raw_alpha = None;
+        bmp
+    }
+
+    /// Composite this image over a solid `background` using the alpha of each pixel,
+    /// giving an opaque 32 bpp image. The alpha of the background is ignored.
+    pub fn flatten(&self, background: Rgbx) -> Bitmap {
+        let mut bmp = self.to_direct();
+        let blend = |fg: u8, bg: u8, a: u8| 
+            ((fg as u32 * a as u32 + bg as u32 * (0xff - a as u32) + 0x7f) / 0xff) as u8;
+        for p in bmp.pixels.iter_mut() {
+            let c = Rgbx::from_pixel(*p);
+            *p = Rgbx(blend(c.0, background.0, c.3), blend(c.1, background.1, c.3),
+                      blend(c.2, background.2, c.3), 0xff).to_pixel();
+        }
+        bmp.raw_alpha = None;
+        bmp
+    }
+
+    /// The number of fully transparent pixels, which is 0 for images without alpha
+    pub fn count_transparent_pixels(&self) -> usize {
+        if self.dib.bpp < 32 { return 0 }
+        self.pixels.iter().filter(|&&p| Rgbx::from_pixel(p).3 == 0).count()
+    }
+
+    /// Whether any pixel is not fully opaque. Only 32 and 64 bpp images can have alpha.
+    pub fn has_transparency(&self) -> bool {
+        self.dib.bpp >= 32 && self.pixels.iter().any(|&p| Rgbx::from_pixel(p).3 < 0xff)
+    }
+
+    /// Whether every pixel is a shade of gray, with equal red, green and blue
+    pub fn is_grayscale(&self) -> bool {
+        self.pixels.iter().all(|&p| {
+            let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            c.0 == c.1 && c.1 == c.2
+        })
+    }
+
+    /// Build a bitmap from its pixels in storage (bottom-up) order. Indexed images 
+    /// (up to 8 bpp) need a color table covering every pixel, while direct color 
+    /// images take packed `Rgbx` pixels and no color table.
+    pub fn new(width: u32, height: u32, bpp: u16, 
+               colors: ColorTable, pixels: Pixels) -> Result<Bitmap, BuildError> {
+        match bpp {
+            1 | 2 | 4 | 8 => {
+                if colors.is_empty() { return Err(BuildError::MissingPalette)}
+                if colors.len() > 1 << bpp { return Err(BuildError::TooManyColors)}
+                if pixels.iter().any(|&p| p >= colors.len()) { 
+                    return Err(BuildError::IndexOverflow)
+                }
+            },
+            24 | 32 => {
+                if !colors.is_empty() { return Err(BuildError::PaletteOnDirectImage)}
+            },
+            _ => return Err(BuildError::UnsupportedBpp),
+        }
+        if pixels.len() as u64 != width as u64 * height as u64 { 
+            return Err(BuildError::PixelCountMismatch)
+        }
+        Ok(Bitmap::new_unchecked(width, height, bpp, colors, pixels))
+    }
+
+    /// Build a bitmap with fresh headers for the given format, color table and 
+    /// pixels in storage (bottom-up) order.
+    fn new_unchecked(width: u32, height: u32, bpp: u16, 
+                     colors: ColorTable, pixels: Pixels) -> Bitmap {
+        let size = (Bitmap::stride(width as usize, bpp) * height as usize) as u32;
+        let offset = 14 + 40 + 4 * colors.len() as u32;
+        Bitmap {
+            header: Header { size: offset + size, reserved: 0, offset: offset },
+            dib: Dib {
+                width: width,
+                height: height,
+                planes: 1,
+                bpp: bpp,
+                comp: 0,
+                size: size,
+                ppm_x: 0,
+                ppm_y: 0,
+                colors: colors.len() as u32,
+                imp_colors: 0,
+                rgb_masks: None,
+                alpha_mask: None,
+                gamma: None,
+                intent: None,
+                top_down: false,
+                extra: Vec::new(),
+                version: HeaderVersion::Info,
+            },
+            colors: colors,
+            pixels: pixels,
+            raw_alpha: None,
+            raw_dib: Vec::new(),
+        }
+    }
+
+    /// Repeat the image `cols` times horizontally and `rows` times vertically.
+    /// Counts making the result wider or taller than a `u32` are an error.
+    pub fn tile(&self, cols: u32, rows: u32) -> Result<Bitmap, TileError> {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        let width = try!(self.dib.width.checked_mul(cols).ok_or(TileError::DimensionsTooLarge));
+        let height = try!(self.dib.height.checked_mul(rows).ok_or(TileError::DimensionsTooLarge));
+        Ok(self.generate(width, height, |x, y| self.row(y % h)[x % w]))
+    }
+
+    /// Keep every `factor`-th pixel in both dimensions, starting at the top-left one.
+    /// A factor of 0 is an error.
+    pub fn subsample(&self, factor: u32) -> Result<Bitmap, SubsampleError> {
+        if factor == 0 { return Err(SubsampleError::ZeroFactor)}
+        let f = factor as usize;
+        let w = self.dib.width / factor + (self.dib.width % factor != 0) as u32;
+        let h = self.dib.height / factor + (self.dib.height % factor != 0) as u32;
+        Ok(self.generate(w, h, |x, y| self.row(y * f)[x * f]))
+    }
+
+    /// The version of the DIB header of this bitmap
+    pub fn header_version(&self) -> HeaderVersion {
+        self.dib.version
+    }
+
+    /// The corner where the rows of `pixels` start, which is the top-left one for
+    /// files stored top-down.
+    pub fn origin(&self) -> Origin {
+        if self.dib.top_down { Origin::TopLeft } else { Origin::BottomLeft }
+    }
+
+    /// The rendering intent declared by the header, if any
+    pub fn rendering_intent(&self) -> Option<RenderingIntent> {
+        self.dib.intent
+    }
+
+    /// The raw pixel values in storage order, starting at the corner given by `origin`
+    pub fn indices(&self) -> &[usize] {
+        &self.pixels
+    }
+
+    /// The raw pixel values in storage order, for editing in place
+    pub fn indices_mut(&mut self) -> &mut [usize] {
+        &mut self.pixels
+    }
+
+    /// Render this bitmap as an HTML table with a cell per pixel, whose background
+    /// is the pixel color. Images with more than `MAX_HTML_CELLS` pixels are 
+    /// subsampled to fit.
+    pub fn to_html_table(&self) -> String {
+        let pixels = self.dib.width as u64 * self.dib.height as u64;
+        let mut factor = 1;
+        while pixels > MAX_HTML_CELLS as u64 * factor * factor { factor += 1; }
+        let sub;
+        let bmp = if factor == 1 { self } else { 
+            sub = self.subsample(factor as u32).expect("factor is positive"); 
+            &sub 
+        };
+
+        let mut html = String::from("<table>\n");
+        for y in 0..bmp.dib.height as usize {
+            html.push_str("<tr>");
+            for x in 0..bmp.dib.width as usize {
+                let c = bmp.color(x, y);
+                html.push_str(&format!(
+                    "<td style=\"background:#{:02x}{:02x}{:02x}\"></td>", c.0, c.1, c.2));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+        html
+    }
+
+    /// Obtain a read-only view of this bitmap
+    pub fn view(&self) -> BitmapView {
+        BitmapView { bmp: self }
+    }
+
+    fn resize_nearest(&self, width: u32, height: u32) -> Bitmap {
+        let (w, h) = (self.dib.width as usize, self.dib.height as usize);
+        self.generate(width, height, |x, y| 
+            self.row(y * h / height as usize)[x * w / width as usize])
+    }
+
+    /// Build a new bitmap with the format and palette of this one but the given 
+    /// dimensions, taking the pixel at each visual position `(x, y)` from `f`.
+    fn generate<F: Fn(usize, usize) -> usize>(&self, width: u32, height: u32, f: F) -> Bitmap {
+        let mut pixels = Pixels::with_capacity(width as usize * height as usize);
+        for r in 0..height as usize {
+            let y = height as usize - 1 - r;
+            for x in 0..width as usize {
+                pixels.push(f(x, y));
+            }
+        }
+        let mut dib = self.dib.clone();
+        dib.width = width;
+        dib.height = height;
+        dib.top_down = false;
+        let mut bmp = Bitmap { 
+            header: self.header.clone(), 
+            dib: dib, 
+            colors: self.colors.clone(), 
+            pixels: pixels, 
+            raw_alpha: None,
+            raw_dib: Vec::new(),
+        };
+        bmp.refresh_headers();
+        bmp
+    }
+
+    /// Write this bitmap as a BMP file encoded at the given bpp. Images are 
+    /// written with their color table at up to 8 bpp, which must fit in that bpp,
+    /// and as direct color above. Nothing is written if the image cannot be encoded.
+    pub fn write<W: io::Write>(&self, out: &mut W, bpp: u16) -> io::Result<()> {
+        let (cols, rows) = (self.dib.width as usize, self.dib.height as usize);
+        if bpp <= 8 && self.colors.len() > 1 << bpp {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "color table exceeds the bits per pixel"))
+        }
+        try!(self.check_encodable(bpp).map_err(|e| 
+            io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        let colors = if bpp <= 8 { &self.colors[..] } else { &[] };
+        try!(out.write_all(&Bitmap::encode_headers(
+            cols, rows, bpp, colors, self.dib.ppm_x, self.dib.ppm_y)));
+
+        for y in (0..rows).rev() {
+            let row = try!(self.encode_row(self.row(y), bpp).map_err(|e| 
+                io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+            try!(out.write_all(&row));
+        }
+        Ok(())
+    }
+
+    /// Save this bitmap to a file at the given bpp. Indexed depths get a color table
+    /// of the colors in use if the current one does not fit, which fails if there
+    /// are more colors than the depth can index.
+    pub fn save_as<P: AsRef<Path>>(&self, path: P, bpp: u16) -> Result<(), SaveError> {
+        let indexed = match bpp {
+            1 | 2 | 4 | 8 => true,
+            24 | 32 => false,
+            _ => return Err(SaveError::UnsupportedBpp),
+        };
+        let remapped;
+        let bmp = if !indexed || (self.is_indexed() && self.colors.len() <= 1 << bpp) { 
+            self 
+        } else {
+            let mut seen = HashMap::new();
+            let mut palette = ColorTable::new();
+            for &p in self.pixels.iter() {
+                let c = self.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+                let c = Rgbx(c.0, c.1, c.2, 0);
+                if seen.insert(c.to_pixel(), ()).is_none() {
+                    if palette.len() == 1 << bpp { return Err(SaveError::TooManyColors)}
+                    palette.push(c);
+                }
+            }
+            remapped = self.to_indexed_with_palette(&palette);
+            &remapped
+        };
+        let mut file = try!(fs::File::create(path));
+        Ok(try!(bmp.write(&mut file, bpp)))
+    }
+
+    /// Compute the size in bytes of this bitmap written at the given bpp.
+    pub fn encoded_size(&self, bpp: u16) -> usize {
+        let ncolors = if bpp <= 8 { self.colors.len() } else { 0 };
+        14 + 40 + 4 * ncolors + Bitmap::stride(self.dib.width as usize, bpp) * self.dib.height as usize
+    }
+
+    /// Encode the file header, the DIB and the color table of a BMP file.
+    fn encode_headers(cols: usize, rows: usize, bpp: u16, colors: &[Rgbx], 
+                      ppm_x: u32, ppm_y: u32) -> Vec<u8> {
+        let ncolors = colors.len();
+        let offset = 14 + 40 + 4 * ncolors;
+        let size = Bitmap::stride(cols, bpp) * rows;
+
+        let mut buff = Vec::with_capacity(offset);
+        buff.push(0x42);
+        buff.push(0x4d);
+        put_dword(&mut buff, (offset + size) as u32);
+        put_dword(&mut buff, 0);
+        put_dword(&mut buff, offset as u32);
+
+        put_dword(&mut buff, 40);
+        put_dword(&mut buff, cols as u32);
+        put_dword(&mut buff, rows as u32);
+        put_word(&mut buff, 1);
+        put_word(&mut buff, bpp);
+        put_dword(&mut buff, 0);
+        put_dword(&mut buff, size as u32);
+        put_dword(&mut buff, ppm_x);
+        put_dword(&mut buff, ppm_y);
+        put_dword(&mut buff, ncolors as u32);
+        put_dword(&mut buff, 0);
+
+        for color in colors {
+            buff.push(color.2);
+            buff.push(color.1);
+            buff.push(color.0);
+            buff.push(color.3);
+        }
+        buff
+    }
+
+    fn stride(cols: usize, bpp: u16) -> usize {
+        ((bpp as usize * cols + 31) / 32) * 4
+    }
+
+    fn row(&self, y: usize) -> &[usize] {
+        let start = self.index_of(0, y);
+        &self.pixels[start..start + self.dib.width as usize]
+    }
+
+    /// The position in `pixels` of the pixel at visual position `(x, y)`
+    fn index_of(&self, x: usize, y: usize) -> usize {
+        // Rows are stored as they come in the file, usually bottom-up
+        let r = if self.dib.top_down { y } else { self.dib.height as usize - 1 - y };
+        r * self.dib.width as usize + x
+    }
+
+    fn encode_row(&self, row: &[usize], bpp: u16) -> Result<Vec<u8>, ScanlineError> {
+        match bpp {
+            1 | 2 | 4 | 8 => Bitmap::encode_indices(row, bpp),
+            24 | 32 => {
+                let mut buff = vec![0u8; Bitmap::stride(row.len(), bpp)];
+                let nbytes = bpp as usize / 8;
+                for (c, &p) in row.iter().enumerate() {
+                    let color = match self.resolve(p) {
+                        Some(color) => color,
+                        None => return Err(ScanlineError::IndexOverflow),
+                    };
+                    buff[c * nbytes] = color.2;
+                    buff[c * nbytes + 1] = color.1;
+                    buff[c * nbytes + 2] = color.0;
+                    if nbytes == 4 { buff[c * nbytes + 3] = color.3; }
+                }
+                Ok(buff)
+            },
+            _ => Err(ScanlineError::UnsupportedBpp),
+        }
+    }
+
+    /// Check that every pixel can be encoded at the given bpp, as `encode_row` 
+    /// requires, before any output is produced
+    fn check_encodable(&self, bpp: u16) -> Result<(), ScanlineError> {
+        let encodable = match bpp {
+            1 | 2 | 4 | 8 => self.pixels.iter().all(|&p| p >> bpp == 0),
+            24 | 32 => self.pixels.iter().all(|&p| self.resolve(p).is_some()),
+            _ => return Err(ScanlineError::UnsupportedBpp),
+        };
+        if encodable { Ok(()) } else { Err(ScanlineError::IndexOverflow) }
+    }
+
+    fn encode_indices(row: &[usize], bpp: u16) -> Result<Vec<u8>, ScanlineError> {
+        let mut buff = vec![0u8; Bitmap::stride(row.len(), bpp)];
+        let bits = bpp as usize;
+        let ppb = 8 / bits;
+        for (c, &p) in row.iter().enumerate() {
+            if p >> bits != 0 { return Err(ScanlineError::IndexOverflow)}
+            buff[c / ppb] |= (p << (8 - bits * (c % ppb + 1))) as u8;
+        }
+        Ok(buff)
+    }
+
+    /// The number of color table entries, which is the maximum for the bpp when 
+    /// an indexed image declares 0 colors
+    fn table_len(dib: &Dib) -> usize {
+        if dib.colors == 0 && dib.bpp <= 8 { 1 << dib.bpp } else { dib.colors as usize }
+    }
+
+    fn check_layout(hd: &Header, dib: &Dib, ncolors: usize) -> Result<(), LoadError> {
+        let offset = hd.offset as u64;
+
+        // Pixel data cannot overlap the headers
+        let headers = Bitmap::table_end(dib, 0);
+        if offset < headers { return Err(LoadError::CorruptLayout(headers, offset))}
+
+        // Nor the color table, computed in 64 bits so a corrupt color count cannot overflow
+        let end = Bitmap::table_end(dib, ncolors);
+        if end > offset { Err(LoadError::CorruptLayout(end, offset)) }
+        else { Ok(()) }
+    }
+
+    /// Replace an implausible number of color table entries, too large for the bpp
+    /// or overrunning the pixel data, with the number of entries that fit before the
+    /// pixel data, which is also recorded in the DIB. Returns the entries to read.
+    fn infer_colors(hd: &Header, dib: &mut Dib, ncolors: usize) -> usize {
+        let headers = Bitmap::table_end(dib, 0);
+        let too_many = dib.bpp <= 8 && ncolors as u64 > 1 << dib.bpp;
+        let overrun = Bitmap::table_end(dib, ncolors) > hd.offset as u64;
+        if (too_many || overrun) && hd.offset as u64 >= headers {
+            let entry = Bitmap::table_end(dib, 1) - headers;
+            dib.colors = ((hd.offset as u64 - headers) / entry) as u32;
+            dib.colors as usize
+        } else { ncolors }
+    }
+
+    /// A non-zero image size must match the padded pixel rows, while a zero size
+    /// says nothing and is always accepted.
+    fn check_size(dib: &Dib, align: usize) -> Result<(), LoadError> {
+        if dib.size == 0 { return Ok(()) }
+        let (_, ebytes) = try!(Bitmap::pixel_section(
+            dib.width as usize, dib.height as usize, dib.bpp, align));
+        if dib.size as u64 != ebytes as u64 { 
+            Err(LoadError::SizeMismatch(dib.size as u64, ebytes as u64)) 
+        } else { Ok(()) }
+    }
+
+    /// Bitmaps have a single color plane
+    fn check_planes(dib: &Dib) -> Result<(), LoadError> {
+        if dib.planes != 1 { Err(LoadError::InvalidPlanes(dib.planes)) }
+        else { Ok(()) }
+    }
+
+    /// Some encoders write no color planes, meaning the only one there can be
+    fn infer_planes(dib: &mut Dib) {
+        if dib.planes == 0 { dib.planes = 1; }
+    }
+
+    /// Only uncompressed (BI_RGB) and bit field (BI_BITFIELDS) pixels can be decoded
+    fn check_compression(dib: &Dib) -> Result<(), LoadError> {
+        match dib.comp {
+            0 | 3 => Ok(()),
+            comp => Err(LoadError::UnsupportedCompression(comp)),
+        }
+    }
+
+    /// Indexed images cannot be resolved without a color table
+    fn check_palette(dib: &Dib, ct: &ColorTable) -> Result<(), LoadError> {
+        if dib.bpp <= 8 && ct.is_empty() { Err(LoadError::MissingPalette) }
+        else { Ok(()) }
+    }
+
+    fn read_section<R: io::Read>(input: &mut R, ebytes: usize) -> Result<Vec<u8>, LoadError> {
+        // Grow the buffer as data arrives so a corrupt size cannot force a huge allocation
+        let mut buff = Vec::new();
+        let nbytes = try!(input.by_ref().take(ebytes as u64).read_to_end(&mut buff));
+        
+        if nbytes != ebytes { Err(LoadError::UnexpectedEof)}
+        else { Ok(buff) }
+    }
+
+    /// Read and discard exactly `n` bytes, for inputs that cannot seek
+    fn skip_bytes<R: io::Read>(input: &mut R, n: u64) -> Result<(), LoadError> {
+        let mut buff = [0u8; 512];
+        let mut left = n;
+        while left > 0 {
+            let chunk = if left < buff.len() as u64 { left as usize } else { buff.len() };
+            match try!(input.read(&mut buff[..chunk])) {
+                0 => return Err(LoadError::UnexpectedEof),
+                nbytes => left -= nbytes as u64,
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the row stride, with rows padded to a multiple of `align` bytes, and
+    /// the total size of the pixel data, failing if they do not fit in a `usize`.
+    /// An alignment of 0 is taken as 1.
+    fn pixel_section(cols: usize, rows: usize, bpp: u16, 
+                     align: usize) -> Result<(usize, usize), LoadError> {
+        let nbytes = try!((bpp as usize).checked_mul(cols)
+            .and_then(|bits| bits.checked_add(7))
+            .map(|bits| bits / 8)
+            .ok_or(LoadError::DimensionsTooLarge));
+        Bitmap::aligned_section(nbytes, rows, align)
+    }
+
+    /// Compute the row stride and the total size of the pixel data as `pixel_section`
+    /// does, given the bytes of each row without padding.
+    fn aligned_section(nbytes: usize, rows: usize, 
+                       align: usize) -> Result<(usize, usize), LoadError> {
+        let align = if align == 0 { 1 } else { align };
+        let rbytes = try!(nbytes.checked_add(align - 1)
+            .map(|n| n / align * align)
+            .ok_or(LoadError::DimensionsTooLarge));
+        let ebytes = try!(rbytes.checked_mul(rows).ok_or(LoadError::DimensionsTooLarge));
+        Ok((rbytes, ebytes))
+    }
+
+    fn read_header<R: io::Read>(input: &mut R, order: ByteOrder) -> Result<Header, LoadError> {
+        // Tell an input with no data at all from a truncated one
+        let mut buff = Vec::new();
+        match try!(input.by_ref().take(14).read_to_end(&mut buff)) {
+            0 => return Err(LoadError::EmptyInput),
+            14 => {},
+            _ => return Err(LoadError::UnexpectedEof),
+        }
+
+        // First two bytes must be `BM` in ASCII
+        if buff[0] != 0x42 || buff[1] != 0x4d { return Err(LoadError::BadMagic)}
+
+        // Read the size, reserved and offset
+        let size = dword!(buff, 2, order);
+        let reserved = dword!(buff, 6, order);
+        let offset = dword!(buff, 10, order);
+
+        Ok(Header { size: size, reserved: reserved, offset: offset })
+    }
+
+    /// Read the DIB along with its bytes as they are in the input
+    fn read_dib<R: io::Read>(
+            input: &mut R, order: ByteOrder) -> Result<(Dib, Vec<u8>), LoadError> {
+        let mut raw = try!(Bitmap::read_section(input, 4));
+
+        // The indicated DIB length must be that of BITMAPCOREHEADER or at least 40, 
+        // the bytes beyond those of BITMAPINFOHEADER are kept as they are up to the 
+        // size of a V5 header
+        let dib_size = dword!(raw, 0, order);
+        if !is_supported_dib_size(dib_size) { return Err(LoadError::UnsupportedDib)}
+        let dib = if dib_size == 12 {
+            raw.extend(try!(Bitmap::read_section(input, 8)));
+            Bitmap::parse_core_dib(&raw, order)
+        } else {
+            let dib_size = dib_size as usize;
+            raw.extend(try!(Bitmap::read_section(input, dib_size - 4)));
+            Bitmap::parse_info_dib(&raw, order)
+        };
+        match dib.bpp {
+            1 | 2 | 4 | 8 | 16 | 24 | 32 | 64 => Ok((dib, raw)),
+            _ => Err(LoadError::UnsupportedBpp),
+        }
+    }
+
+    /// Parse a BITMAPCOREHEADER, whose images are stored bottom-up with a full 
+    /// color table up to 8 bpp
+    fn parse_core_dib(buff: &[u8], order: ByteOrder) -> Dib {
+        Dib { 
+            width: word!(buff, 4, order) as u32, 
+            height: word!(buff, 6, order) as u32, 
+            planes: word!(buff, 8, order),
+            bpp: word!(buff, 10, order),
+            comp: 0,
+            size: 0,
+            ppm_x: 0,
+            ppm_y: 0,
+            colors: 0,
+            imp_colors: 0,
+            rgb_masks: None,
+            alpha_mask: None,
+            gamma: None,
+            intent: None,
+            top_down: false,
+            extra: Vec::new(),
+            version: HeaderVersion::Core,
+        }
+    }
+
+    /// Parse a BITMAPINFOHEADER or any of its later extensions
+    fn parse_info_dib(buff: &[u8], order: ByteOrder) -> Dib {
+        let extra = &buff[40..];
+
+        // Headers of 52 bytes and beyond carry the channel masks
+        let rgb_masks = if extra.len() >= 12 { 
+            Some((dword!(extra, 0, order), dword!(extra, 4, order), dword!(extra, 8, order))) 
+        } else { None };
+        let alpha_mask = if extra.len() >= 16 { Some(dword!(extra, 12, order)) } else { None };
+
+        // V4 headers carry the gammas as 16.16 fixed point numbers
+        let fixed = |n: u32| n as f64 / 65536.0;
+        let gamma = if extra.len() >= 68 {
+            Some((fixed(dword!(extra, 56, order)), 
+                  fixed(dword!(extra, 60, order)), 
+                  fixed(dword!(extra, 64, order))))
+        } else { None };
+
+        // V5 headers carry the rendering intent
+        let intent = if extra.len() >= 72 { 
+            Some(RenderingIntent::from_u32(dword!(extra, 68, order))) 
+        } else { None };
+
+        // Read the fields
+        let width = dword!(buff, 4, order);
+        // A negative height denotes an image stored top-down
+        let raw_height = dword!(buff, 8, order) as i32;
+        let top_down = raw_height < 0;
+        let height = if top_down { raw_height.wrapping_neg() as u32 } else { raw_height as u32 };
+        // Pixels per meter are signed, negative values are bogus and left unset
+        let ppm = |n: u32| if (n as i32) < 0 { 0 } else { n };
+
+        Dib { 
+            width: width, 
+            height: height, 
+            planes: word!(buff, 12, order),
+            bpp: word!(buff, 14, order),
+            comp: dword!(buff, 16, order),
+            size: dword!(buff, 20, order),
+            ppm_x: ppm(dword!(buff, 24, order)),
+            ppm_y: ppm(dword!(buff, 28, order)),
+            colors: dword!(buff, 32, order),
+            imp_colors: dword!(buff, 36, order),
+            rgb_masks: rgb_masks,
+            alpha_mask: alpha_mask,
+            gamma: gamma,
+            intent: intent,
+            top_down: top_down,
+            extra: extra.to_vec(),
+            version: match buff.len() {
+                0...55 => HeaderVersion::Info,
+                56...107 => HeaderVersion::V3,
+                108...123 => HeaderVersion::V4,
+                _ => HeaderVersion::V5,
+            },
+        }
+    }
+
+    /// Read the color table following the DIB, whose entries take 3 bytes with
+    /// core headers and 4 bytes with any later header.
+    fn read_dib_color_table<R: io::Read>(
+            input: &mut R, dib: &Dib, ncolors: usize) -> Result<ColorTable, LoadError> {
+        match dib.version {
+            HeaderVersion::Core => Bitmap::read_color_table_rgb(input, ncolors),
+            _ => Bitmap::read_color_table(input, ncolors),
+        }
+    }
+
+    /// Read a color table of 3 byte BGR entries, as used by BITMAPCOREHEADER
+    fn read_color_table_rgb<R: io::Read>(
+            input: &mut R, ncolors: usize) -> Result<ColorTable, LoadError> {
+        let ebytes = try!(ncolors.checked_mul(3).ok_or(LoadError::UnexpectedEof));
+        let buff = try!(Bitmap::read_section(input, ebytes));
+        Ok(buff.chunks(3).map(|e| Rgbx(e[2], e[1], e[0], 0)).collect())
+    }
+
+    fn read_color_table<R: io::Read>(
+            input: &mut R, ncolors: usize) -> Result<ColorTable, LoadError> {
+        let ebytes = try!(ncolors.checked_mul(4).ok_or(LoadError::UnexpectedEof));
+        let buff = try!(Bitmap::read_section(input, ebytes));
+        let mut table = ColorTable::new();
+        for i in 0..ncolors {
+            table.push(Rgbx::from_bgrx_u32(dword!(buff, 4*i)));
+        }
+        Ok(table)
+    }
+
+    fn read_pixels<R: io::Read>(
+            input: &mut R, dib: &Dib, align: usize, pixels: &mut Pixels, 
+            progress: &mut FnMut(f32)) -> Result<(), LoadError> {
+        let (cols, rows) = (dib.width as usize, dib.height as usize);
+        let decoder = try!(Bitmap::row_decoder(dib));
+        try!(Bitmap::decode_rows(input, cols, rows, align, &*decoder, pixels, progress));
+        if rows == 0 { progress(1.0); }
+        Ok(())
+    }
+
+    /// The decoder of the pixel rows of the given format
+    fn row_decoder(dib: &Dib) -> Result<Box<RowDecoder>, LoadError> {
+        match dib.bpp {
+            1 => Ok(Box::new(Decoder1bpp)),
+            4 => Ok(Box::new(Decoder4bpp)),
+            24 => Ok(Box::new(Decoder24bpp)),
+            32 => Ok(Box::new(Decoder32bpp { masks: Bitmap::bitfields(dib) })),
+            64 => Ok(Box::new(Decoder64bpp)),
+            _ => Err(LoadError::UnsupportedBpp),
+        }
+    }
+
+    /// Decode `rows` rows of `cols` pixels, padded to a multiple of `align` bytes
+    fn decode_rows<R: io::Read>(
+            input: &mut R, cols: usize, rows: usize, align: usize, decoder: &RowDecoder,
+            pixels: &mut Pixels, progress: &mut FnMut(f32)) -> Result<(), LoadError> {
+        let nbytes = try!(decoder.stride(cols).ok_or(LoadError::DimensionsTooLarge));
+        let (rbytes, ebytes) = try!(Bitmap::aligned_section(nbytes, rows, align));
+        let buff = try!(Bitmap::read_section(input, ebytes));
+
+        for r in 0..rows {
+            let start = r * rbytes;
+            pixels.extend(decoder.decode_row(&buff[start..start + nbytes], cols));
+            progress((r + 1) as f32 / rows as f32);
+        }
+        Ok(())
+    }
+
+    /// The R, G, B and A masks of a BI_BITFIELDS image, if it is one.
+    fn bitfields(dib: &Dib) -> Option<(u32, u32, u32, u32)> {
+        match (dib.comp, dib.rgb_masks) {
+            (3, Some((r, g, b))) => Some((r, g, b, dib.alpha_mask.unwrap_or(0))),
+            _ => None,
+        }
+    }
+
+    /// Extract the channel selected by `mask` from a pixel, scaled to 8 bits.
+    fn extract_bits(n: u32, mask: u32) -> u8 {
+        if mask == 0 { return 0 }
+        let shift = mask.trailing_zeros();
+        let max = (mask >> shift) as u64;
+        (((n & mask) >> shift) as u64 * 255 / max) as u8
+    }
+}
+
+/// A decoder of the pixel rows of some pixel format
+trait RowDecoder {
+    /// The number of bytes of a row of `cols` pixels without padding, if it fits in memory
+    fn stride(&self, cols: usize) -> Option<usize>;
+
+    /// Decode the pixels of a row, given its bytes without padding
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize>;
+}
+
+struct Decoder1bpp;
+
+impl RowDecoder for Decoder1bpp {
+    fn stride(&self, cols: usize) -> Option<usize> {
+        cols.checked_add(7).map(|n| n / 8)
+    }
+
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize> {
+        (0..cols).map(|c| ((row[c / 8] >> (7 - c % 8)) & 1) as usize).collect()
+    }
+}
+
+struct Decoder4bpp;
+
+impl RowDecoder for Decoder4bpp {
+    fn stride(&self, cols: usize) -> Option<usize> {
+        cols.checked_add(1).map(|n| n / 2)
+    }
+
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize> {
+        (0..cols).map(|c| {
+            let b = row[c / 2];
+            (if c % 2 == 0 { b >> 4 } else { b & 0x0f }) as usize
+        }).collect()
+    }
+}
+
+struct Decoder32bpp {
+    masks: Option<(u32, u32, u32, u32)>,
+}
+
+impl RowDecoder for Decoder32bpp {
+    fn stride(&self, cols: usize) -> Option<usize> {
+        cols.checked_mul(4)
+    }
+
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize> {
+        (0..cols).map(|c| {
+            let i = 4 * c;
+            let color = match self.masks {
+                Some((rm, gm, bm, am)) => {
+                    let n = dword!(row, i);
+                    Rgbx(Bitmap::extract_bits(n, rm), Bitmap::extract_bits(n, gm),
+                         Bitmap::extract_bits(n, bm), Bitmap::extract_bits(n, am))
+                },
+                None => Rgbx(row[i+2], row[i+1], row[i], row[i+3]),
+            };
+            color.to_pixel()
+        }).collect()
+    }
+}
+
+/// Pixels stored as B, G, R triplets with no alpha channel.
+struct Decoder24bpp;
+
+impl RowDecoder for Decoder24bpp {
+    fn stride(&self, cols: usize) -> Option<usize> {
+        cols.checked_mul(3)
+    }
+
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize> {
+        (0..cols).map(|c| {
+            let i = 3 * c;
+            Rgbx(row[i+2], row[i+1], row[i], 0xff).to_pixel()
+        }).collect()
+    }
+}
+
+/// Pixels of 16 bits per channel, which are kept with 8 bits per channel so the 
+/// lower 8 bits of each are lost.
+struct Decoder64bpp;
+
+impl RowDecoder for Decoder64bpp {
+    fn stride(&self, cols: usize) -> Option<usize> {
+        cols.checked_mul(8)
+    }
+
+    fn decode_row(&self, row: &[u8], cols: usize) -> Vec<usize> {
+        (0..cols).map(|c| {
+            let channel = |k: usize| (word!(row, 8 * c + 2 * k) >> 8) as u8;
+            Rgbx(channel(2), channel(1), channel(0), channel(3)).to_pixel()
+        }).collect()
+    }
+}
+
+/// A bitmap being decoded a few rows at a time, see `Bitmap::read_partial`
+#[derive(Debug)]
+pub struct DecodeState {
+    prelude: Prelude,
+    align: usize,
+    pixels: Pixels,
+    rows: u32,
+}
+
+impl DecodeState {
+
+    /// The number of pixel rows decoded so far, in storage order
+    pub fn rows_decoded(&self) -> u32 {
+        self.rows
+    }
+
+    /// Whether every pixel row has been decoded
+    pub fn is_complete(&self) -> bool {
+        self.rows == self.prelude.dib.height
+    }
+
+    /// The decoded bitmap, if every pixel row has been decoded
+    pub fn into_bitmap(self) -> Option<Bitmap> {
+        if !self.is_complete() { return None }
+        Some(self.prelude.into_bitmap(self.pixels))
+    }
+}
+
+/// The headers and color table of a bitmap, everything preceding its pixels
+#[derive(Debug)]
+struct Prelude {
+    header: Header,
+    dib: Dib,
+    raw_dib: Vec<u8>,
+    colors: ColorTable,
+}
+
+impl Prelude {
+
+    /// Complete the bitmap with its pixels in storage order
+    fn into_bitmap(self, mut pixels: Pixels) -> Bitmap {
+        let raw_alpha = Bitmap::split_alpha(&mut pixels, &self.dib);
+        Bitmap { 
+            header: self.header, 
+            dib: self.dib, 
+            colors: self.colors, 
+            pixels: pixels, 
+            raw_alpha: raw_alpha, 
+            raw_dib: self.raw_dib,
+        }
+    }
+}
+
+/// A read-only view of a bitmap, in visual coordinates
+pub struct BitmapView<'a> {
+    bmp: &'a Bitmap,
+}
+
+impl<'a> BitmapView<'a> {
+
+    /// The pixels of visual row `y`, being 0 the top row
+    pub fn row(&self, y: u32) -> &'a [usize] {
+        self.bmp.row(y as usize)
+    }
+
+    /// The resolved color at visual position `(x, y)`
+    pub fn color_at(&self, x: u32, y: u32) -> Rgbx {
+        self.bmp.color(x as usize, y as usize)
+    }
+}
+
+/// A BMP writer that encodes the pixel rows as they are provided. 
+/// Rows are given bottom-up, in the same order they are stored in the file.
+pub struct BmpWriter<W: io::Write> {
+    out: W,
+    width: u32,
+    height: u32,
+    bpp: u16,
+    rows: u32,
+}
+
+impl<W: io::Write> BmpWriter<W> {
+
+    /// Create a new writer, emitting the headers and the color table. The bits
+    /// per pixel are the fewest (1, 4 or 8) that can index the given palette.
+    pub fn new(mut out: W, width: u32, height: u32, 
+               palette: &ColorTable) -> io::Result<BmpWriter<W>> {
+        let bpp = match palette.len() {
+            0...2 => 1,
+            3...16 => 4,
+            17...256 => 8,
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "palette exceeds 256 colors")),
+        };
+        try!(out.write_all(&Bitmap::encode_headers(
+            width as usize, height as usize, bpp, palette, 0, 0)));
+        Ok(BmpWriter { out: out, width: width, height: height, bpp: bpp, rows: 0 })
+    }
+
+    /// Write the next row of pixel indices
+    pub fn write_row(&mut self, row: &[usize]) -> io::Result<()> {
+        if row.len() != self.width as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "row width mismatch"))
+        }
+        if self.rows == self.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many rows"))
+        }
+        let buff = try!(Bitmap::encode_indices(row, self.bpp).map_err(|e| 
+            io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        try!(self.out.write_all(&buff));
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Finish the image, failing if fewer rows than its height were written
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.rows != self.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing rows"))
+        }
+        try!(self.out.flush());
+        Ok(self.out)
+    }
+}
+
+/// A builder of indexed bitmaps from rows of colors, given top-down.
+pub struct PalettizedBuilder {
+    rows: Vec<Vec<Rgbx>>,
+    quantize: bool,
+}
+
+impl PalettizedBuilder {
+
+    /// Create a builder with no rows
+    pub fn new() -> PalettizedBuilder {
+        PalettizedBuilder { rows: Vec::new(), quantize: false }
+    }
+
+    /// Reduce the image to 256 colors instead of failing when it has more
+    pub fn quantize(mut self, enabled: bool) -> PalettizedBuilder {
+        self.quantize = enabled;
+        self
+    }
+
+    /// Add the next row of colors below the previous ones
+    pub fn row(mut self, row: Vec<Rgbx>) -> PalettizedBuilder {
+        self.rows.push(row);
+        self
+    }
+
+    /// Build the bitmap, at the fewest bits per pixel (1, 4 or 8) that can index
+    /// its colors.
+    pub fn build(self) -> Result<Bitmap, BuildError> {
+        let width = self.rows.first().map_or(0, |row| row.len());
+        if self.rows.iter().any(|row| row.len() != width) { 
+            return Err(BuildError::RowWidthMismatch)
+        }
+        let (width, height) = (width as u32, self.rows.len() as u32);
+
+        let mut palette = ColorTable::new();
+        let mut indices = HashMap::new();
+        for row in self.rows.iter() {
+            for c in row.iter() {
+                let c = Rgbx(c.0, c.1, c.2, 0);
+                if !indices.contains_key(&c.to_pixel()) {
+                    indices.insert(c.to_pixel(), palette.len());
+                    palette.push(c);
+                }
+            }
+        }
+
+        if palette.len() > 256 {
+            if !self.quantize { return Err(BuildError::TooManyColors)}
+            let mut pixels = Pixels::new();
+            for row in self.rows.iter().rev() {
+                pixels.extend(row.iter().map(|c| Rgbx(c.0, c.1, c.2, 0xff).to_pixel()));
+            }
+            return Ok(Bitmap::new_unchecked(width, height, 32, ColorTable::new(), pixels)
+                .quantize(256))
+        }
+
+        let mut pixels = Pixels::new();
+        for row in self.rows.iter().rev() {
+            pixels.extend(row.iter().map(|c| indices[&Rgbx(c.0, c.1, c.2, 0).to_pixel()]));
+        }
+        let bpp = match palette.len() {
+            0...2 => 1,
+            3...16 => 4,
+            _ => 8,
+        };
+        Ok(Bitmap::new_unchecked(width, height, bpp, palette, pixels))
+    }
+}
+
+#[cfg(feature = "image-crate")]
+impl From<Bitmap> for image::DynamicImage {
+    fn from(bmp: Bitmap) -> image::DynamicImage {
+        let img = image::RgbaImage::from_fn(bmp.dib.width, bmp.dib.height, |x, y| {
+            let p = bmp.row(y as usize)[x as usize];
+            let c = bmp.resolve(p).unwrap_or(Rgbx(0, 0, 0, 0xff));
+            image::Rgba([c.0, c.1, c.2, c.3])
+        });
+        image::DynamicImage::ImageRgba8(img)
+    }
+}
+
+#[cfg(feature = "png")]
+impl Bitmap {
+
+    /// Encode this bitmap as PNG. Indexed images keep their color table in an
+    /// indexed PNG, while direct color images are RGB (or RGBA if they have alpha).
+    pub fn to_png_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let (w, h) = (self.dib.width, self.dib.height);
+        let mut data = Vec::new();
+        let color = if self.is_indexed() {
+            if self.colors.len() > 256 { return Err(EncodeError::TooManyColors)}
+            for y in 0..h as usize { 
+                data.extend(self.row(y).iter().map(|&p| p as u8)); 
+            }
+            png::ColorType::Indexed
+        } else if self.dib.alpha_mask.map_or(false, |m| m != 0) {
+            for y in 0..h as usize {
+                for x in 0..w as usize {
+                    let c = self.color(x, y);
+                    data.extend([c.0, c.1, c.2, c.3].iter().cloned());
+                }
+            }
+            png::ColorType::Rgba
+        } else {
+            for y in 0..h as usize {
+                for x in 0..w as usize {
+                    let c = self.color(x, y);
+                    data.extend([c.0, c.1, c.2].iter().cloned());
+                }
+            }
+            png::ColorType::Rgb
+        };
+
+        let mut buff = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut buff, w, h);
+            encoder.set_color(color);
+            encoder.set_depth(png::BitDepth::Eight);
+            if self.is_indexed() {
+                encoder.set_palette(self.colors.iter()
+                    .flat_map(|c| vec![c.0, c.1, c.2].into_iter())
+                    .collect::<Vec<u8>>());
+            }
+            let mut writer = try!(encoder.write_header());
+            try!(writer.write_image_data(&data));
+            try!(writer.finish());
+        }
+        Ok(buff)
+    }
+}
+
+/// A future reading a bitmap from an async stream, see `Bitmap::read_async`
+#[cfg(feature = "async")]
+pub struct ReadAsync<R> {
+    input: R,
+    buff: Vec<u8>,
+    // The bytes to buffer before deciding how many more the bitmap takes
+    want: usize,
+}
+
+#[cfg(feature = "async")]
+impl Bitmap {
+
+    /// Read a bitmap from an async stream without blocking, and then decode it as 
+    /// `read` does. Only the bytes of the bitmap are read, so the stream can go on
+    /// with other data and need not be closed.
+    pub fn read_async<R: AsyncRead + Unpin>(input: R) -> ReadAsync<R> {
+        ReadAsync { input: input, buff: Vec::new(), want: 18 }
+    }
+
+    /// The length of the bitmap starting with `buff`, as far as it tells. It takes
+    /// the file header and the DIB size first, then the rest of the DIB, then 
+    /// everything up to the pixels and finally the pixels.
+    fn async_len(buff: &[u8]) -> Result<usize, LoadError> {
+        if buff.len() < 18 { return Ok(18) }
+        let dib_end = 14 + ::std::cmp::min(dword!(buff, 14), 124) as usize;
+        if buff.len() < dib_end { return Ok(dib_end) }
+
+        let mut input = io::Cursor::new(buff);
+        let hd = try!(Bitmap::read_header(&mut input, ByteOrder::Little));
+        if buff.len() < hd.offset as usize { return Ok(hd.offset as usize) }
+
+        let opts = DecodeOptions::default();
+        input.set_position(0);
+        let pre = try!(Bitmap::read_prelude(&mut input, true, &opts));
+        let decoder = try!(Bitmap::row_decoder(&pre.dib));
+        let nbytes = try!(decoder.stride(pre.dib.width as usize)
+            .ok_or(LoadError::DimensionsTooLarge));
+        let (_, ebytes) = try!(Bitmap::aligned_section(
+            nbytes, pre.dib.height as usize, opts.row_alignment));
+        (hd.offset as usize).checked_add(ebytes).ok_or(LoadError::DimensionsTooLarge)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> Future for ReadAsync<R> {
+    type Output = ::Result<Bitmap>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::Result<Bitmap>> {
+        let this = &mut *self;
+        let mut chunk = [0u8; 4096];
+        loop {
+            if this.buff.len() == this.want {
+                let want = match Bitmap::async_len(&this.buff) {
+                    Ok(want) => want,
+                    Err(err) => return Poll::Ready(Err(err)),
+                };
+                if want <= this.buff.len() {
+                    return Poll::Ready(Bitmap::read(&mut io::Cursor::new(&this.buff[..])))
+                }
+                this.want = want;
+            }
+            let nbytes = {
+                let len = ::std::cmp::min(chunk.len(), this.want - this.buff.len());
+                let mut rbuf = ReadBuf::new(&mut chunk[..len]);
+                match Pin::new(&mut this.input).poll_read(cx, &mut rbuf) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(LoadError::Io(err))),
+                    Poll::Ready(Ok(())) => rbuf.filled().len(),
+                }
+            };
+            // A stream ending early fails the decode as a truncated input would
+            if nbytes == 0 { 
+                return Poll::Ready(Bitmap::read(&mut io::Cursor::new(&this.buff[..])))
+            }
+            this.buff.extend(chunk[..nbytes].iter().cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A builder of the bytes of BMP files for decoding tests
+    struct BmpBytesBuilder {
+        width: u32,
+        height: u32,
+        bpp: u16,
+        colors: Vec<Rgbx>,
+        rows: Vec<Vec<u8>>,
+    }
+
+    impl BmpBytesBuilder {
+
+        fn new() -> BmpBytesBuilder {
+            BmpBytesBuilder { width: 0, height: 0, bpp: 4, colors: Vec::new(), rows: Vec::new() }
+        }
+
+        fn width(mut self, width: u32) -> BmpBytesBuilder {
+            self.width = width;
+            self
+        }
+
+        fn height(mut self, height: u32) -> BmpBytesBuilder {
+            self.height = height;
+            self
+        }
+
+        fn bpp(mut self, bpp: u16) -> BmpBytesBuilder {
+            self.bpp = bpp;
+            self
+        }
+
+        /// Add the next entry of the color table
+        fn color(mut self, r: u8, g: u8, b: u8) -> BmpBytesBuilder {
+            self.colors.push(Rgbx(r, g, b, 0));
+            self
+        }
+
+        /// Add the next row in storage (bottom-up) order, given the pixel indices 
+        /// for indexed images or the packed colors for direct color ones
+        fn pixel_row(mut self, pixels: &[usize]) -> BmpBytesBuilder {
+            let mut row = Vec::new();
+            if self.bpp <= 8 {
+                let bits = self.bpp as usize;
+                for (c, &p) in pixels.iter().enumerate() {
+                    let shift = 8 - bits * (c % (8 / bits) + 1);
+                    if shift == 8 - bits { row.push(0); }
+                    *row.last_mut().unwrap() |= (p << shift) as u8;
+                }
+            } else {
+                for &p in pixels.iter() {
+                    let c = Rgbx::from_pixel(p);
+                    row.extend([c.2, c.1, c.0].iter().cloned());
+                    if self.bpp == 32 { row.push(c.3); }
+                }
+            }
+            while row.len() % 4 != 0 { row.push(0); }
+            self.rows.push(row);
+            self
+        }
+
+        fn build(self) -> Vec<u8> {
+            let size = self.rows.iter().map(|row| row.len()).sum::<usize>() as u32;
+            let offset = 14 + 40 + 4 * self.colors.len() as u32;
+            let mut buff = vec![0x42, 0x4d];
+            put_dword(&mut buff, offset + size);
+            put_dword(&mut buff, 0);
+            put_dword(&mut buff, offset);
+            put_dword(&mut buff, 40);
+            put_dword(&mut buff, self.width);
+            put_dword(&mut buff, self.height);
+            put_word(&mut buff, 1);
+            put_word(&mut buff, self.bpp);
+            put_dword(&mut buff, 0);
+            put_dword(&mut buff, size);
+            put_dword(&mut buff, 0);
+            put_dword(&mut buff, 0);
+            put_dword(&mut buff, self.colors.len() as u32);
+            put_dword(&mut buff, 0);
+            for c in self.colors.iter() { buff.extend([c.2, c.1, c.0, c.3].iter().cloned()); }
+            for row in self.rows.iter() { buff.extend(row.iter().cloned()); }
+            buff
+        }
+    }
+
+    /// The sample file with a larger DIB whose bytes beyond the first 40 are `extra`
+    fn sample_with_dib_extra(extra: &[u8]) -> Vec<u8> {
+        let mut buff = sample();
+        let n = extra.len() as u8;
+        buff[2] += n;
+        buff[10] += n;
+        buff[14] += n;
+        let pixels = buff.split_off(54);
+        buff.extend(extra.iter().cloned());
+        buff.extend(pixels);
+        buff
+    }
+
+    fn sample() -> Vec<u8> {
+        vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ]
+    }
+
+    #[test]
+    #[should_fail(expected = "BadMagic")]
+    fn should_fail_read_bad_magic() {
+        let buff: Vec<u8> = vec![
+            0xcc, 0x0dd, // <-- 0xccdd is a bad magic
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_read_unexpected_eof_in_header() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x00, 0x00, 0x00, 0x00,
+                                    // <-- more bytes expected
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "UnsupportedDib")]
+    fn should_fail_read_unsupported_dib() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0xaa, 0xbb, 0xcc, 0xdd, // <-- 0xaabbccdd is a unknown size
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_read_unexpected_eof_in_color_table() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+                                    // <-- more bytes expected
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_read_unexpected_eof_in_pixel_store() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+                                    // <-- more bytes expected
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "UnsupportedBpp")]
+    fn should_fail_read_unsupported_pixel_format() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x08, 0x00, // <-- 0x0800 unsupported
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    fn should_read() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    fn should_extract_scanline_bytes() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let row = bmp.scanline_bytes(0, 4).unwrap();
+        assert_eq!(row, vec![0x23, 0x10, 0x00, 0x00]);
+    }
+
+    #[test]
+    #[should_fail(expected = "RowOutOfRange")]
+    fn should_fail_extract_scanline_bytes_out_of_range() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.scanline_bytes(3, 4).unwrap();
+    }
+
+    #[test]
+    fn should_write_thumbnail() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(100, 50, |x, y| (x + y) % 4);
+        let mut out = Vec::new();
+        bmp.write_thumbnail(&mut out, 10).unwrap();
+
+        let thumb = Bitmap::read(&mut Cursor::new(out)).unwrap();
+        assert_eq!(thumb.dib.width, 10);
+        assert_eq!(thumb.dib.height, 5);
+        assert_eq!(thumb.dib.bpp, 24);
+        assert_eq!(thumb.to_color_grid(), bmp.resize_nearest(10, 5).to_color_grid());
+    }
+
+    #[test]
+    fn should_read_32bpp_reserved_byte_as_opaque() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x3e, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x36, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x20, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x08, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+
+            0x10, 0x20, 0x30, 0x00, // <-- reserved bytes are zero
+            0x40, 0x50, 0x60, 0x00,
+        ];
+        let bmp = Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(bmp.resolve(bmp.pixels[0]), Some(Rgbx(0x30, 0x20, 0x10, 0xff)));
+        assert_eq!(bmp.resolve(bmp.pixels[1]), Some(Rgbx(0x60, 0x50, 0x40, 0xff)));
+        assert_eq!(bmp.raw_alpha, Some(vec![0x00, 0x00]));
+    }
+
+    #[test]
+    fn should_trim() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(4, 4, |x, y| if x > 0 && x < 3 && y > 0 && y < 3 { x } else { 0 });
+        let trimmed = bmp.trim(0);
+        assert_eq!(trimmed.dib.width, 2);
+        assert_eq!(trimmed.dib.height, 2);
+        assert_eq!(trimmed.row(0), [1, 2]);
+        assert_eq!(trimmed.row(1), [1, 2]);
+    }
+
+    #[test]
+    #[should_fail(expected = "CorruptLayout")]
+    fn should_fail_read_color_table_overrun() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff, // <-- color table overruns pixel offset
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    fn should_iterate_dib_fields() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert!(bmp.dib.fields().any(|field| field == ("bpp", 4)));
+    }
+
+    #[test]
+    fn should_read_into_existing_buffer() {
+        let mut buf = Pixels::new();
+        Bitmap::read_into(&mut Cursor::new(sample()), &mut buf).unwrap();
+        let first = buf.clone();
+        let (_, dib, colors) = Bitmap::read_into(&mut Cursor::new(sample()), &mut buf).unwrap();
+        assert_eq!(buf, first);
+        assert_eq!(buf.len(), (dib.width * dib.height) as usize);
+        assert_eq!(colors.len(), 4);
+        assert_eq!(buf, Bitmap::read(&mut Cursor::new(sample())).unwrap().pixels);
+    }
+
+    #[test]
+    fn should_detect_bmp() {
+        assert!(is_bmp(&sample()[..18]));
+    }
+
+    #[test]
+    fn should_not_detect_bmp() {
+        assert!(!is_bmp(b"GIF89a\x01\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00"));
+        assert!(!is_bmp(b"BM"));
+        for &size in [0u8, 39, 125].iter() {
+            let mut data = sample();
+            data[14] = size;
+            assert!(!is_bmp(&data[..18]));
+            assert!(Bitmap::read(&mut Cursor::new(data)).is_err());
+        }
+    }
+
+    #[test]
+    fn should_threshold() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(2, 1, |x, _| x);
+        bmp.colors = vec![Rgbx(0, 0, 0, 0), Rgbx(128, 128, 128, 0)];
+        assert_eq!(bmp.threshold(127), vec![false, true]);
+        assert_eq!(bmp.threshold(128), vec![false, false]);
+    }
+
+    #[test]
+    #[should_fail(expected = "CorruptLayout(54, 10)")]
+    fn should_fail_read_offset_overlapping_headers() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x52, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x00, 0x00, 0x00, // <-- offset 10 points inside the headers
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ];
+        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_round_trip_through_bincode() {
+        use bincode;
+
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let encoded = bincode::serialize(&bmp).unwrap();
+        let decoded: Bitmap = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, bmp);
+    }
+
+    #[test]
+    #[cfg(feature = "image-crate")]
+    fn should_convert_into_dynamic_image() {
+        use image::{DynamicImage, GenericImageView, Rgba};
+
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let img = DynamicImage::from(bmp);
+        assert_eq!(img.dimensions(), (3, 3));
+        assert_eq!(img.get_pixel(0, 0), Rgba([0x00, 0xff, 0x00, 0xff]));
+    }
+
+    #[test]
+    fn should_stream_rows_with_writer() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let mut writer = BmpWriter::new(Vec::new(), 3, 3, &bmp.colors).unwrap();
+        for y in (0..3).rev() {
+            writer.write_row(bmp.row(y)).unwrap();
+        }
+        let out = writer.finish().unwrap();
+
+        let reloaded = Bitmap::read(&mut Cursor::new(out)).unwrap();
+        assert_eq!(reloaded.colors, bmp.colors);
+        assert_eq!(reloaded.pixels, bmp.pixels);
+    }
+
+    #[test]
+    #[should_fail(expected = "missing rows")]
+    fn should_fail_finish_writer_with_missing_rows() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let mut writer = BmpWriter::new(Vec::new(), 3, 3, &bmp.colors).unwrap();
+        writer.write_row(bmp.row(2)).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn should_invert_twice() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.invert();
+        assert_eq!(bmp.colors[0], Rgbx(255, 255, 255, 0));
+        bmp.invert();
+        assert_eq!(bmp.colors[0], Rgbx(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn should_read_cur() {
+        let buff: Vec<u8> = vec![
+            0x00, 0x00,
+            0x02, 0x00,
+            0x01, 0x00,
+
+            0x02, 0x02, 0x02, 0x00,
+            0x01, 0x00, // <-- hotspot at (1, 0)
+            0x00, 0x00,
+            0x40, 0x00, 0x00, 0x00,
+            0x16, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00, // <-- twice the height for XOR and AND masks
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0x00,
+
+            0x10, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let (bmp, hotspot) = Bitmap::read_cur(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(hotspot, (1, 0));
+        assert_eq!(bmp.dib.width, 2);
+        assert_eq!(bmp.dib.height, 2);
+        assert_eq!(bmp.row(0), [0, 1]);
+        assert_eq!(bmp.row(1), [1, 0]);
+    }
+
+    #[test]
+    fn should_interleave_rows() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let top = bmp.generate(2, 2, |x, y| 2 * y + x);
+        let bottom = bmp.generate(2, 2, |x, y| 8 + 2 * y + x);
+        let interleaved = top.interleave_rows(&bottom).unwrap();
+        assert_eq!(interleaved.dib.width, 2);
+        assert_eq!(interleaved.dib.height, 4);
+        assert_eq!(interleaved.row(0), [0, 1]);
+        assert_eq!(interleaved.row(1), [8, 9]);
+        assert_eq!(interleaved.row(2), [2, 3]);
+        assert_eq!(interleaved.row(3), [10, 11]);
+    }
+
+    #[test]
+    #[should_fail(expected = "WidthMismatch")]
+    fn should_fail_interleave_rows_with_different_widths() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.interleave_rows(&bmp.generate(2, 3, |_, _| 0)).unwrap();
+    }
+
+    #[test]
+    fn should_never_panic_reading_garbage() {
+        let valid = sample();
+
+        // Every truncation of a valid file
+        for n in 0..valid.len() {
+            assert!(Bitmap::read(&mut Cursor::new(valid[..n].to_vec())).is_err());
+        }
+
+        // Random corruptions of a valid file, from a fixed-seed LCG
+        let mut seed = 0x2545f491u32;
+        let mut next = || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); seed >> 16 };
+        for _ in 0..5000 {
+            let mut buff = valid.clone();
+            for _ in 0..(next() % 8 + 1) {
+                let i = next() as usize % buff.len();
+                buff[i] = next() as u8;
+            }
+            let n = next() as usize % (buff.len() + 1);
+            let _ = Bitmap::read(&mut Cursor::new(buff[..n].to_vec()));
+        }
+
+        // Pure noise
+        for len in 0..200 {
+            let buff: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let _ = Bitmap::read(&mut Cursor::new(buff));
+        }
+    }
+
+    #[test]
+    fn should_fail_read_huge_dimensions() {
+        let mut buff = sample();
+        for i in 18..26 { buff[i] = 0xff; }
+        assert!(Bitmap::read(&mut Cursor::new(buff)).is_err());
+    }
+
+    #[test]
+    fn should_concat_horizontal() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let left = bmp.generate(2, 2, |x, y| 2 * y + x);
+        let right = bmp.generate(2, 2, |x, y| 8 + 2 * y + x);
+        let joined = left.concat_horizontal(&right).unwrap();
+        assert_eq!(joined.dib.width, 4);
+        assert_eq!(joined.dib.height, 2);
+        assert_eq!(joined.row(0), [0, 1, 8, 9]);
+        assert_eq!(joined.row(1), [2, 3, 10, 11]);
+    }
+
+    #[test]
+    fn should_concat_horizontal_as_direct_color() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let left = bmp.generate(1, 1, |_, _| 3);
+        let mut right = bmp.generate(1, 1, |_, _| 3);
+        right.colors[3] = Rgbx(1, 2, 3, 0);
+        let joined = left.concat_horizontal(&right).unwrap();
+        assert_eq!(joined.dib.bpp, 32);
+        assert_eq!(joined.resolve(joined.row(0)[0]), Some(Rgbx(0xff, 0, 0, 0xff)));
+        assert_eq!(joined.resolve(joined.row(0)[1]), Some(Rgbx(1, 2, 3, 0xff)));
+    }
+
+    #[test]
+    #[should_fail(expected = "HeightMismatch")]
+    fn should_fail_concat_horizontal_with_different_heights() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.concat_horizontal(&bmp.generate(3, 2, |_, _| 0)).unwrap();
+    }
+
+    #[test]
+    fn should_concat_vertical() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let top = bmp.generate(3, 1, |x, _| x);
+        let bottom = bmp.generate(3, 1, |x, _| 8 + x);
+        let stacked = top.concat_vertical(&bottom).unwrap();
+        assert_eq!(stacked.dib.width, 3);
+        assert_eq!(stacked.dib.height, 2);
+        assert_eq!(stacked.row(0), [0, 1, 2]);
+        assert_eq!(stacked.row(1), [8, 9, 10]);
+    }
+
+    #[test]
+    #[should_fail(expected = "WidthMismatch")]
+    fn should_fail_concat_vertical_with_different_widths() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.concat_vertical(&bmp.generate(2, 3, |_, _| 0)).unwrap();
+    }
+
+    #[test]
+    fn should_find_closest_index() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.closest_index(Rgbx(230, 20, 10, 0)), 3);
+        assert_eq!(bmp.closest_index(Rgbx(10, 10, 10, 0)), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn should_load_gz() {
+        use std::env;
+        use std::fs;
+        use std::io::Write;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&sample()).unwrap();
+        let path = env::temp_dir().join("pbm-should-load-gz.bmp.gz");
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let bmp = Bitmap::load_gz(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    #[should_fail(expected = "Io")]
+    fn should_fail_load_corrupt_gz() {
+        use std::env;
+        use std::fs;
+
+        let path = env::temp_dir().join("pbm-should-fail-load-corrupt-gz.bmp.gz");
+        fs::write(&path, vec![0x1f, 0x8b, 0x08, 0x00, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let result = Bitmap::load_gz(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn should_read_color_table_in_bgr_order() {
+        let buff: Vec<u8> = vec![
+            0xff, 0x00, 0x00, 0x00, // <-- blue
+            0x00, 0x00, 0xff, 0x7f, // <-- red, with the reserved byte set
+        ];
+        let table = Bitmap::read_color_table(&mut Cursor::new(buff), 2).unwrap();
+        assert_eq!(table[0].b(), 255);
+        assert_eq!(table[0].r(), 0);
+        assert_eq!(table[1].r(), 255);
+        assert_eq!(table[1].b(), 0);
+        assert_eq!(table[1].x(), 0x7f);
+    }
+
+    #[test]
+    fn should_read_rgb_triple_color_table() {
+        let buff: Vec<u8> = vec![
+            0xff, 0x00, 0x00, // <-- blue
+            0x00, 0x80, 0xff, // <-- orange
+            0x00, // <-- first byte of the pixels
+        ];
+        let table = Bitmap::read_color_table_rgb(&mut Cursor::new(buff), 2).unwrap();
+        assert_eq!(table, [Rgbx(0, 0, 0xff, 0), Rgbx(0xff, 0x80, 0, 0)]);
+    }
+
+    #[test]
+    fn should_enumerate_pixels() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let pixels: Vec<_> = bmp.enumerate_pixels().collect();
+        assert_eq!(pixels.len(), 9);
+        assert_eq!(pixels[0], (0, 0, Rgbx(0, 0xff, 0, 0xff)));
+        assert_eq!(pixels[8], (2, 2, Rgbx(0, 0xff, 0, 0xff)));
+    }
+
+    #[test]
+    fn should_quantize_keeping_important_colors() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(3, 3, |x, y| if x == 0 && y == 0 { 0 } else if y == 2 { 2 } else { 3 });
+        bmp.dib.imp_colors = 0;
+        assert_eq!(bmp.quantize(2).colors, vec![Rgbx(0xff, 0, 0, 0), Rgbx(0, 0xff, 0, 0)]);
+
+        bmp.dib.imp_colors = 1;
+        let quantized = bmp.quantize(2);
+        assert_eq!(quantized.colors, vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0, 0, 0)]);
+        assert_eq!(quantized.dib.important_colors(), 1);
+        assert_eq!(quantized.row(0), [0, 1, 1]);
+    }
+
+    #[test]
+    fn should_compute_encoded_size() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        for &bpp in [4, 8, 24, 32].iter() {
+            let mut out = Vec::new();
+            bmp.write(&mut out, bpp).unwrap();
+            assert_eq!(bmp.encoded_size(bpp), out.len());
+        }
+        assert_eq!(bmp.encoded_size(4), sample().len());
+    }
+
+    #[test]
+    fn should_read_unknown_dib_size_leniently() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x5e, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x52, 0x00, 0x00, 0x00,
+
+            0x34, 0x00, 0x00, 0x00, // <-- 52 bytes, 12 more than BITMAPINFOHEADER
             0x03, 0x00, 0x00, 0x00,
             0x03, 0x00, 0x00, 0x00,
             0x01, 0x00, 
@@ -265,132 +3614,1309 @@ mod test {
             0x13, 0x0b, 0x00, 0x00,
             0x04, 0x00, 0x00, 0x00,
             0x04, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
         ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        let bmp = Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(bmp.dib.extra, vec![
+            0x00, 0x00, 0xff, 0x00, 0x00, 0xff, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00]);
+        assert_eq!(bmp.pixels, Bitmap::read(&mut Cursor::new(sample())).unwrap().pixels);
+    }
+
+    #[test]
+    fn should_pad_to() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(2, 2, |x, y| 1 + 2 * y + x);
+        let padded = bmp.pad_to(4, 4, 0).unwrap();
+        assert_eq!(padded.row(0), [0, 0, 0, 0]);
+        assert_eq!(padded.row(1), [0, 1, 2, 0]);
+        assert_eq!(padded.row(2), [0, 3, 4, 0]);
+        assert_eq!(padded.row(3), [0, 0, 0, 0]);
+
+        let anchored = bmp.pad_to_anchored(4, 4, 0, Anchor::TopLeft).unwrap();
+        assert_eq!(anchored.row(0), [1, 2, 0, 0]);
+        assert_eq!(anchored.row(3), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    #[should_fail(expected = "TargetTooSmall")]
+    fn should_fail_pad_to_smaller_size() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.pad_to(2, 4, 0).unwrap();
+    }
+
+    #[test]
+    fn should_read_v3_header_with_alpha() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x4a, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x38, 0x00, 0x00, 0x00, // <-- 56 bytes BITMAPV3INFOHEADER
+            0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x20, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0xff, // <-- alpha mask
+
+            0x10, 0x20, 0x30, 0x80,
+        ];
+        let bmp = Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(bmp.dib.rgb_masks, Some((0x00ff0000, 0x0000ff00, 0x000000ff)));
+        assert_eq!(bmp.dib.alpha_mask, Some(0xff000000));
+        assert_eq!(bmp.resolve(bmp.pixels[0]), Some(Rgbx(0x30, 0x20, 0x10, 0x80)));
+    }
+
+    #[test]
+    fn should_read_v3_header_with_bitfields() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x4a, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x46, 0x00, 0x00, 0x00,
+
+            0x38, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x20, 0x00, 
+            0x03, 0x00, 0x00, 0x00, // <-- BI_BITFIELDS
+            0x04, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00, // <-- RGBA byte order
+            0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00,
+            0x00, 0x00, 0x00, 0xff,
+
+            0x10, 0x20, 0x30, 0x80,
+        ];
+        let bmp = Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(bmp.resolve(bmp.pixels[0]), Some(Rgbx(0x10, 0x20, 0x30, 0x80)));
+    }
+
+    #[test]
+    fn should_hash_rows() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let before = bmp.row_hashes();
+        bmp.pixels[4] = 1;
+        let after = bmp.row_hashes();
+        assert_eq!(before.len(), 3);
+        assert!(before[0] == after[0]);
+        assert!(before[1] != after[1]);
+        assert!(before[2] == after[2]);
+    }
+
+    #[test]
+    #[should_fail(expected = "MissingPalette")]
+    fn should_fail_read_indexed_without_palette() {
+        let buff: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x42, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x36, 0x00, 0x00, 0x00,
+
+            0x28, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x00, 
+            0x04, 0x00, 
+            0x00, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x13, 0x0b, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0x00, // <-- 4 colors, but no room before the pixels
+            0x00, 0x00, 0x00, 0x00,
+
+            0x13, 0x20, 0x00, 0x00,
+            0x30, 0x30, 0x00, 0x00,
+            0x23, 0x10, 0x00, 0x00,
+        ];
+        let opts = DecodeOptions { lenient: true, ..Default::default() };
+        Bitmap::read_with_options(&mut Cursor::new(buff), &opts).unwrap();
+    }
+
+    #[test]
+    fn should_read_full_color_table_when_no_colors_declared() {
+        let mut data = sample();
+        data[46] = 0x00;
+        let gap = vec![0u8; 4 * (16 - 4)];
+        for (i, &b) in gap.iter().enumerate() { data.insert(70 + i, b); }
+        data[10] += gap.len() as u8;
+        let bmp = Bitmap::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(bmp.colors.len(), 16);
+        assert_eq!(bmp.colors[3], Rgbx(0xff, 0x00, 0x00, 0x00));
+        assert_eq!(bmp.pixels, Bitmap::read(&mut Cursor::new(sample())).unwrap().pixels);
+
+        // Without room for the 16 entries the layout is corrupt
+        let mut data = sample();
+        data[46] = 0x00;
+        assert!(Bitmap::read(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn should_convert_to_rgba_f32() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let floats = bmp.to_rgba_f32();
+        assert_eq!(floats.len(), 36);
+        assert_eq!(&floats[..4], [0.0, 1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn should_cycle_palette() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let original = bmp.colors.clone();
+        bmp.cycle_palette(0, 4, 1).unwrap();
+        assert_eq!(bmp.colors[1], original[0]);
+        assert_eq!(bmp.colors[2], original[1]);
+        assert_eq!(bmp.colors[3], original[2]);
+        assert_eq!(bmp.colors[0], original[3]);
+        bmp.cycle_palette(0, 4, -1).unwrap();
+        assert_eq!(bmp.colors, original);
+    }
+
+    #[test]
+    #[should_fail(expected = "RangeOutOfBounds")]
+    fn should_fail_cycle_palette_out_of_range() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.cycle_palette(2, 4, 1).unwrap();
+    }
+
+    #[test]
+    fn should_blit() {
+        let src = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let mut dst = src.generate(3, 3, |_, _| 0);
+        dst.blit(&src, (1, 0, 1, 1), (2, 2)).unwrap();
+        assert_eq!(dst.row(2), [0, 0, 3]);
+
+        // Clipped to the destination bounds
+        dst.blit(&src, (0, 0, 3, 3), (2, 0)).unwrap();
+        assert_eq!(dst.row(0), [0, 0, 2]);
+        assert_eq!(dst.row(2), [0, 0, 1]);
+    }
+
+    #[test]
+    #[should_fail(expected = "FormatMismatch")]
+    fn should_fail_blit_with_different_palettes() {
+        let src = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let mut dst = src.to_direct();
+        dst.blit(&src, (0, 0, 1, 1), (0, 0)).unwrap();
+    }
+
+    #[test]
+    fn should_read_with_progress() {
+        let mut progress = Vec::new();
+        Bitmap::read_with_progress(&mut Cursor::new(sample()), |p| progress.push(p)).unwrap();
+        assert_eq!(progress.len(), 3);
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(progress[2], 1.0);
+    }
+
+    #[test]
+    fn should_read_v4_gamma() {
+        let mut extra = vec![0u8; 68];
+        for i in 0..3 {
+            // 2.2 in 16.16 fixed point
+            extra[56 + 4 * i] = 0x33;
+            extra[57 + 4 * i] = 0x33;
+            extra[58 + 4 * i] = 0x02;
+        }
+        let bmp = Bitmap::read(&mut Cursor::new(sample_with_dib_extra(&extra))).unwrap();
+        let (r, g, b) = bmp.gamma().unwrap();
+        assert!((r - 2.2).abs() < 1e-4);
+        assert!((g - 2.2).abs() < 1e-4);
+        assert!((b - 2.2).abs() < 1e-4);
+        assert!(Bitmap::read(&mut Cursor::new(sample())).unwrap().gamma().is_none());
+    }
+
+    #[test]
+    fn should_resize_box() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(2, 2, |x, y| (x + y) % 2);
+        bmp.colors = vec![Rgbx(0, 0, 0, 0), Rgbx(255, 255, 255, 0)];
+        let resized = bmp.resize_box(1, 1);
+        assert_eq!(resized.dib.width, 1);
+        assert_eq!(resized.dib.height, 1);
+        assert_eq!(resized.color(0, 0), Rgbx(127, 127, 127, 255));
+    }
+
+    #[test]
+    fn should_resize_box_empty_image() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap().generate(0, 3, |_, _| 0);
+        let resized = bmp.resize_box(2, 2);
+        assert_eq!((resized.dib.width, resized.dib.height), (2, 2));
+        assert_eq!(resized.pixels, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn should_extract_channel() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.extract_channel(Channel::Red), vec![
+            0x00, 0xff, 0x00,
+            0xff, 0x00, 0xff,
+            0x00, 0xff, 0x00,
+        ]);
+        assert_eq!(bmp.extract_channel(Channel::Alpha), vec![0xff; 9]);
+    }
+
+    #[test]
+    fn should_build_palettized_bitmap() {
+        let bmp = PalettizedBuilder::new()
+            .row(vec![Rgbx(255, 0, 0, 0), Rgbx(0, 255, 0, 0)])
+            .row(vec![Rgbx(0, 0, 255, 0), Rgbx(255, 255, 255, 0)])
+            .build().unwrap();
+        assert_eq!(bmp.dib.width, 2);
+        assert_eq!(bmp.dib.height, 2);
+        assert_eq!(bmp.dib.bpp, 4);
+        assert_eq!(bmp.colors.len(), 4);
+        assert_eq!(bmp.row(0), [0, 1]);
+        assert_eq!(bmp.row(1), [2, 3]);
+        assert_eq!(bmp.color(1, 1), Rgbx(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn should_build_palettized_bitmap_with_too_many_colors() {
+        let row: Vec<Rgbx> = (0..300).map(|i| Rgbx(i as u8, (i >> 8) as u8, 0, 0)).collect();
+        let builder = PalettizedBuilder::new().row(row.clone());
+        assert!(builder.build().is_err());
+
+        let bmp = PalettizedBuilder::new().quantize(true).row(row).build().unwrap();
+        assert_eq!(bmp.dib.bpp, 8);
+        assert_eq!(bmp.colors.len(), 256);
+    }
+
+    #[test]
+    fn should_read_negative_ppm_as_unset() {
+        let mut buff = sample();
+        for i in 38..42 { buff[i] = 0xff; }
+        let bmp = Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        assert_eq!(bmp.dib.ppm_x, 0);
+        assert_eq!(bmp.dpi(), (DEFAULT_DPI, 72));
+    }
+
+    #[test]
+    fn should_tile() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap()
+            .generate(2, 2, |x, y| 2 * y + x);
+        let tiled = bmp.tile(2, 2).unwrap();
+        assert_eq!(tiled.dib.width, 4);
+        assert_eq!(tiled.dib.height, 4);
+        assert_eq!(tiled.colors, bmp.colors);
+        assert_eq!(tiled.row(1)[3], bmp.row(1)[1]);
+        assert_eq!(tiled.row(2), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    #[should_fail(expected = "DimensionsTooLarge")]
+    fn should_fail_tile_too_large() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.tile(::std::u32::MAX, 1).unwrap();
+    }
+
+    #[test]
+    fn should_read_v5_rendering_intent() {
+        let mut extra = vec![0u8; 84];
+        extra[68] = 0x04; // <-- LCS_GM_IMAGES
+        let bmp = Bitmap::read(&mut Cursor::new(sample_with_dib_extra(&extra))).unwrap();
+        assert_eq!(bmp.rendering_intent(), Some(RenderingIntent::Perceptual));
+        assert_eq!(Bitmap::read(&mut Cursor::new(sample())).unwrap().rendering_intent(), None);
+    }
+
+    #[test]
+    fn should_view_rows_without_copying() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let view = bmp.view();
+        assert_eq!(view.row(0), [2, 3, 1]);
+        assert_eq!(view.row(0).as_ptr(), bmp.pixels[6..].as_ptr());
+        assert_eq!(view.color_at(0, 0), Rgbx(0, 0xff, 0, 0xff));
+    }
+
+    #[test]
+    #[should_fail(expected = "SizeMismatch(8, 12)")]
+    fn should_reject_size_mismatch_when_strict() {
+        let mut data = sample();
+        data[34] = 0x08;
+        assert!(Bitmap::read(&mut Cursor::new(data.clone())).is_ok());
+        let opts = DecodeOptions { strict: true, ..Default::default() };
+        Bitmap::read_with_options(&mut Cursor::new(data), &opts).unwrap();
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn should_encode_indexed_png() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let bytes = bmp.to_png_bytes().unwrap();
+        let decoder = ::png::Decoder::new(Cursor::new(bytes));
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut data).unwrap();
+        assert_eq!((frame.width, frame.height), (3, 3));
+        assert_eq!(frame.color_type, ::png::ColorType::Indexed);
+        let palette = reader.info().palette.clone().unwrap();
+        let p = data[0] as usize;
+        assert_eq!(p, 2);
+        assert_eq!(&palette[3 * p..3 * p + 3], [0x00, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn should_detect_grayscale() {
+        let ramp = (0..4).map(|v| Rgbx(v * 0x55, v * 0x55, v * 0x55, 0xff).to_pixel()).collect();
+        let gray = Bitmap::new_unchecked(4, 1, 24, ColorTable::new(), ramp);
+        assert!(gray.is_grayscale());
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert!(!bmp.is_grayscale());
+    }
+
+    #[test]
+    fn should_build_valid_bitmaps() {
+        let bmp = Bitmap::new(2, 1, 4, vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0xff, 0xff, 0)], 
+                              vec![0, 1]).unwrap();
+        assert_eq!(bmp.color(1, 0), Rgbx(0xff, 0xff, 0xff, 0xff));
+        let bmp = Bitmap::new(1, 1, 24, ColorTable::new(), vec![0x11223300]).unwrap();
+        assert_eq!(bmp.color(0, 0), Rgbx(0x11, 0x22, 0x33, 0x00));
+    }
+
+    #[test]
+    #[should_fail(expected = "PaletteOnDirectImage")]
+    fn should_reject_palette_on_direct_image() {
+        Bitmap::new(1, 1, 24, vec![Rgbx(0, 0, 0, 0)], vec![0]).unwrap();
+    }
+
+    #[test]
+    #[should_fail(expected = "IndexOverflow")]
+    fn should_reject_index_overflow() {
+        Bitmap::new(2, 1, 4, vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0xff, 0xff, 0)], 
+                    vec![0, 2]).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_read_async() {
+        let data = sample();
+        let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let bmp = rt.block_on(Bitmap::read_async(&data[..])).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
+    }
+
+    /// A stream serving a few bytes at a time that must not be read past its data
+    #[cfg(feature = "async")]
+    struct OpenStream(Vec<u8>);
+
+    #[cfg(feature = "async")]
+    impl AsyncRead for OpenStream {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, 
+                     buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            assert!(!self.0.is_empty(), "read past the end of the bitmap");
+            let n = ::std::cmp::min(5, ::std::cmp::min(buf.remaining(), self.0.len()));
+            buf.put_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_read_async_without_consuming_trailing_bytes() {
+        let mut data = sample();
+        data.extend([1, 2, 3].iter().cloned());
+        let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut input = &data[..];
+        let bmp = rt.block_on(Bitmap::read_async(&mut input)).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
+        assert_eq!(input, [1, 2, 3]);
+
+        let bmp = rt.block_on(Bitmap::read_async(OpenStream(sample()))).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_read_async_truncated() {
+        let data = sample();
+        let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(Bitmap::read_async(&data[..75])).unwrap();
+    }
+
+    #[test]
+    fn should_replace_near_colors() {
+        let pixels = vec![
+            Rgbx(0xfe, 0xff, 0xfd, 0xff).to_pixel(), Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel(),
+            Rgbx(0xff, 0xff, 0xff, 0xff).to_pixel(), Rgbx(0xf0, 0xff, 0xff, 0xff).to_pixel()];
+        let mut bmp = Bitmap::new(4, 1, 32, ColorTable::new(), pixels).unwrap();
+        bmp.replace_color(Rgbx(0xff, 0xff, 0xff, 0xff), Rgbx(0xff, 0xff, 0xff, 0x00), 2);
+        let colors: Vec<Rgbx> = bmp.pixels.iter().map(|&p| Rgbx::from_pixel(p)).collect();
+        assert_eq!(colors, vec![
+            Rgbx(0xff, 0xff, 0xff, 0x00), Rgbx(0x10, 0x20, 0x30, 0xff),
+            Rgbx(0xff, 0xff, 0xff, 0x00), Rgbx(0xf0, 0xff, 0xff, 0xff)]);
+
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.replace_color(Rgbx(0, 0, 0xf0, 0), Rgbx(0xff, 0xff, 0, 0), 0x10);
+        assert_eq!(bmp.colors[1], Rgbx(0xff, 0xff, 0, 0));
+        assert_eq!(bmp.colors[0], Rgbx(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn should_flood_fill_bounded_region() {
+        // A ring of 1s enclosing a 0, surrounded by more 0s
+        let pixels = (0..25).map(|i| {
+            let (x, y) = (i % 5, i / 5);
+            if x >= 1 && x <= 3 && y >= 1 && y <= 3 && (x, y) != (2, 2) { 1 } else { 0 }
+        }).collect();
+        let colors = vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0, 0, 0), Rgbx(0, 0xff, 0, 0)];
+        let mut bmp = Bitmap::new(5, 5, 4, colors, pixels).unwrap();
+        let before = bmp.clone();
+        bmp.flood_fill(2, 2, 2);
+        assert_eq!(bmp.row(2), [0, 1, 2, 1, 0]);
+        for y in 0..5 {
+            for x in 0..5 {
+                if (x, y) != (2, 2) { assert_eq!(bmp.row(y)[x], before.row(y)[x]); }
+            }
+        }
+    }
+
+    #[test]
+    fn should_read_headerless_dib() {
+        let data = sample();
+        let bmp = Bitmap::read_dib_only(&mut Cursor::new(data[14..].to_vec())).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(data)).unwrap());
+    }
+
+    #[test]
+    fn should_pack_palette_bytes() {
+        let colors = vec![Rgbx(0x01, 0x02, 0x03, 0), Rgbx(0x04, 0x05, 0x06, 0)];
+        let bmp = Bitmap::new(2, 1, 1, colors, vec![0, 1]).unwrap();
+        assert_eq!(bmp.palette_bytes(ChannelOrder::Rgb), [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_eq!(bmp.palette_bytes(ChannelOrder::Bgra), 
+                   [0x03, 0x02, 0x01, 0xff, 0x06, 0x05, 0x04, 0xff]);
+    }
+
+    #[test]
+    #[should_fail(expected = "UnsupportedBpp")]
+    fn should_reject_illegal_bpp() {
+        let mut data = sample();
+        data[28] = 0x03;
+        Bitmap::read_dib(&mut Cursor::new(data[14..].to_vec()), ByteOrder::Little).unwrap();
+    }
+
+    #[test]
+    fn should_subsample() {
+        let pixels = (0..16).collect();
+        let colors = (0..16).map(|i| Rgbx(i, i, i, 0)).collect();
+        let bmp = Bitmap::new(4, 4, 4, colors, pixels).unwrap();
+        let sub = bmp.subsample(2).unwrap();
+        assert_eq!((sub.dib.width, sub.dib.height), (2, 2));
+        assert_eq!(sub.row(0), [12, 14]);
+        assert_eq!(sub.row(1), [4, 6]);
+    }
+
+    #[test]
+    #[should_fail(expected = "ZeroFactor")]
+    fn should_fail_subsample_by_zero() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.subsample(0).unwrap();
+    }
+
+    #[test]
+    fn should_skip_gap_before_pixels() {
+        let mut data = sample();
+        data[2] += 16;
+        data[10] += 16;
+        let pixels = data.split_off(70);
+        data.extend([0xaa; 16].iter().cloned());
+        data.extend(pixels);
+        let bmp = Bitmap::read(&mut &data[..]).unwrap();
+        assert_eq!(bmp.pixels, Bitmap::read(&mut Cursor::new(sample())).unwrap().pixels);
+    }
+
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_to_skip_past_the_end() {
+        let data = [0u8; 4];
+        Bitmap::skip_bytes(&mut &data[..], 16).unwrap();
+    }
+
+    #[test]
+    fn should_average_colors() {
+        let colors = vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0xff, 0xff, 0)];
+        let bmp = Bitmap::new(2, 2, 1, colors, vec![0, 0, 1, 1]).unwrap();
+        assert_eq!(bmp.average_color(), Rgbx(0x80, 0x80, 0x80, 0xff));
+    }
+
+    #[test]
+    fn should_read_big_endian_headers() {
+        let mut data = sample();
+        let fields = [(2, 4), (6, 4), (10, 4), (14, 4), (18, 4), (22, 4), (26, 2), 
+                      (28, 2), (30, 4), (34, 4), (38, 4), (42, 4), (46, 4), (50, 4)];
+        for &(i, n) in fields.iter() { data[i..i + n].reverse(); }
+        let opts = DecodeOptions { byte_order: ByteOrder::Big, ..Default::default() };
+        let bmp = Bitmap::read_with_options(&mut Cursor::new(data.clone()), &opts).unwrap();
+        assert_eq!(bmp.raw_dib, &data[14..54]);
+        // The raw DIB keeps the bytes in their original order
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp, Bitmap { raw_dib: bmp.raw_dib.clone(), ..expected });
+    }
+
+    #[test]
+    fn should_map_onto_fixed_palette() {
+        let pixels = vec![
+            Rgbx(0xf0, 0x10, 0x10, 0xff).to_pixel(), Rgbx(0x10, 0x10, 0x10, 0xff).to_pixel(),
+            Rgbx(0x20, 0xe0, 0x20, 0xff).to_pixel(), Rgbx(0x00, 0x00, 0xc0, 0xff).to_pixel()];
+        let bmp = Bitmap::new(4, 1, 24, ColorTable::new(), pixels).unwrap();
+        let palette = vec![Rgbx(0, 0, 0, 0), Rgbx(0, 0, 0xff, 0), 
+                           Rgbx(0, 0xff, 0, 0), Rgbx(0xff, 0, 0, 0)];
+        let indexed = bmp.to_indexed_with_palette(&palette);
+        assert_eq!(indexed.dib.bpp, 4);
+        assert_eq!(indexed.colors, palette);
+        assert_eq!(indexed.pixels, [3, 0, 2, 1]);
+    }
+
+    #[test]
+    fn should_not_map_onto_empty_palette() {
+        let bmp = Bitmap::new(1, 1, 24, ColorTable::new(), 
+                              vec![Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel()]).unwrap();
+        assert_eq!(bmp.to_indexed_with_palette(&ColorTable::new()), bmp);
+    }
+
+    #[test]
+    fn should_read_top_down_bitmap() {
+        let mut data = sample();
+        data[22..26].copy_from_slice(&[0xfd, 0xff, 0xff, 0xff]);
+        let bmp = Bitmap::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(bmp.origin(), Origin::TopLeft);
+        assert_eq!(bmp.dib.height, 3);
+        assert_eq!(bmp.row(0), [1, 3, 2]);
+        assert_eq!(bmp.row(2), [2, 3, 1]);
+
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.origin(), Origin::BottomLeft);
+    }
+
+    #[test]
+    fn should_save_as_indexed() {
+        use std::env;
+        use std::fs;
+
+        let pixels = vec![
+            Rgbx(0xff, 0, 0, 0xff).to_pixel(), Rgbx(0, 0xff, 0, 0xff).to_pixel(),
+            Rgbx(0, 0, 0xff, 0xff).to_pixel(), Rgbx(0xff, 0, 0, 0xff).to_pixel(),
+            Rgbx(0, 0, 0, 0xff).to_pixel(), Rgbx(0, 0xff, 0, 0xff).to_pixel()];
+        let bmp = Bitmap::new(3, 2, 24, ColorTable::new(), pixels).unwrap();
+        let path = env::temp_dir().join("pbm-should-save-as-indexed.bmp");
+        bmp.save_as(&path, 4).unwrap();
+        let saved = Bitmap::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.dib.bpp, 4);
+        assert_eq!(saved.colors.len(), 4);
+        assert_eq!(saved.row_hashes(), bmp.row_hashes());
+    }
+
+    #[test]
+    #[should_fail(expected = "TooManyColors")]
+    fn should_not_save_as_too_small_depth() {
+        use std::env;
+
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let path = env::temp_dir().join("pbm-should-not-save-as-too-small-depth.bmp");
+        bmp.save_as(&path, 1).unwrap();
+    }
+
+    #[test]
+    fn should_read_only_the_palette() {
+        let colors: ColorTable = (0..16).map(|i| Rgbx(i * 16, 0, 0xff - i * 16, 0)).collect();
+        let bmp = Bitmap::new(4, 4, 4, colors.clone(), (0..16).collect()).unwrap();
+        let mut data = Vec::new();
+        bmp.write(&mut data, 4).unwrap();
+        data.truncate(14 + 40 + 4 * 16 + 2);
+        assert_eq!(Bitmap::read_palette(&mut Cursor::new(data)).unwrap(), colors);
+    }
+
+    #[test]
+    fn should_posterize() {
+        let pixels = vec![Rgbx(0x80, 0x80, 0x80, 0xff).to_pixel(), 
+                          Rgbx(0x7f, 0x10, 0xf0, 0xff).to_pixel()];
+        let mut bmp = Bitmap::new(2, 1, 24, ColorTable::new(), pixels).unwrap();
+        bmp.posterize(2);
+        assert_eq!(bmp.color(0, 0), Rgbx(0xff, 0xff, 0xff, 0xff));
+        assert_eq!(bmp.color(1, 0), Rgbx(0x00, 0x00, 0xff, 0xff));
+
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.colors[1] = Rgbx(0x50, 0xa0, 0xff, 0);
+        bmp.posterize(4);
+        assert_eq!(bmp.colors[1], Rgbx(0x55, 0xaa, 0xff, 0));
+    }
+
+    #[test]
+    fn should_read_at_offset() {
+        let mut data = vec![0xee; 32];
+        data.extend(sample());
+        let bmp = Bitmap::read_at(&mut Cursor::new(data), 32).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
     }
 
     #[test]
-    #[should_fail(expected = "UnexpectedEof")]
-    fn should_fail_read_unexpected_eof_in_color_table() {
-        let buff: Vec<u8> = vec![
-            0x42, 0x04d, 
-            0x52, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-            0x46, 0x00, 0x00, 0x00,
+    fn should_map_pixels() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.map_pixels(|_, _, c| Rgbx(0, c.1, c.2, c.3));
+        assert_eq!(bmp.dib.bpp, 32);
+        assert_eq!(bmp.color(1, 0), Rgbx(0, 0, 0, 0xff));
+        assert_eq!(bmp.color(2, 0), Rgbx(0, 0, 0xff, 0xff));
+        assert!(bmp.enumerate_pixels().all(|(_, _, c)| c.0 == 0));
+    }
 
-            0x28, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x01, 0x00, 
-            0x04, 0x00, 
-            0x00, 0x00, 0x00, 0x00,
-            0x0c, 0x00, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
+    #[test]
+    #[should_fail(expected = "EmptyInput")]
+    fn should_fail_on_empty_input() {
+        Bitmap::read(&mut Cursor::new(Vec::new())).unwrap();
+    }
 
-            0x00, 0x00, 0x00, 0x00,
-            0xff, 0x00, 0x00, 0x00,
-                                    // <-- more bytes expected
-        ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    #[test]
+    fn should_compute_luminance_histogram() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let bins = bmp.luminance_histogram();
+        assert_eq!((bins[0], bins[29], bins[76], bins[149]), (1, 2, 4, 2));
+        assert_eq!(bins.iter().sum::<u32>(), 9);
     }
 
     #[test]
-    #[should_fail(expected = "UnexpectedEof")]
-    fn should_fail_read_unexpected_eof_in_pixel_store() {
-        let buff: Vec<u8> = vec![
-            0x42, 0x04d, 
-            0x52, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-            0x46, 0x00, 0x00, 0x00,
+    fn should_adjust_brightness_and_contrast() {
+        let pixels = vec![Rgbx(0x80, 0x80, 0x80, 0xff).to_pixel(), 
+                          Rgbx(0xf0, 0x00, 0x40, 0xff).to_pixel()];
+        let mut bmp = Bitmap::new(2, 1, 24, ColorTable::new(), pixels).unwrap();
+        bmp.adjust(32, 1.0);
+        assert_eq!(bmp.color(0, 0), Rgbx(0xa0, 0xa0, 0xa0, 0xff));
+        assert_eq!(bmp.color(1, 0), Rgbx(0xff, 0x20, 0x60, 0xff));
+        bmp.adjust(0, 2.0);
+        assert_eq!(bmp.color(0, 0), Rgbx(0xc0, 0xc0, 0xc0, 0xff));
+        assert_eq!(bmp.color(1, 0), Rgbx(0xff, 0x00, 0x40, 0xff));
+    }
 
-            0x28, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x01, 0x00, 
-            0x04, 0x00, 
-            0x00, 0x00, 0x00, 0x00,
-            0x0c, 0x00, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
+    #[test]
+    fn should_read_rows_with_custom_alignment() {
+        let data = sample();
+        let mut padded = data[..70].to_vec();
+        for row in data[70..].chunks(4) {
+            padded.extend(row.iter().cloned());
+            padded.extend([0u8; 4].iter().cloned());
+        }
+        let opts = DecodeOptions { row_alignment: 8, ..Default::default() };
+        let bmp = Bitmap::read_with_options(&mut Cursor::new(padded), &opts).unwrap();
+        assert_eq!(bmp.pixels, Bitmap::read(&mut Cursor::new(data)).unwrap().pixels);
+    }
 
-            0x00, 0x00, 0x00, 0x00,
-            0xff, 0x00, 0x00, 0x00,
-            0x00, 0xff, 0x00, 0x00,
-            0x00, 0x00, 0xff, 0x00,
+    #[test]
+    fn should_detect_transparency() {
+        let opaque = Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel();
+        let bmp = Bitmap::new(2, 1, 32, ColorTable::new(), vec![opaque, opaque]).unwrap();
+        assert!(!bmp.has_transparency());
+        let translucent = Rgbx(0x10, 0x20, 0x30, 0x80).to_pixel();
+        let bmp = Bitmap::new(2, 1, 32, ColorTable::new(), vec![opaque, translucent]).unwrap();
+        assert!(bmp.has_transparency());
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert!(!bmp.has_transparency());
+    }
 
-            0x13, 0x20, 0x00, 0x00,
-                                    // <-- more bytes expected
-        ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    #[test]
+    fn should_swap_channels() {
+        let red = Rgbx(0xff, 0x00, 0x00, 0xff).to_pixel();
+        let mut bmp = Bitmap::new(1, 1, 24, ColorTable::new(), vec![red]).unwrap();
+        bmp.swap_channels([Channel::Blue, Channel::Green, Channel::Red]);
+        assert_eq!(bmp.color(0, 0), Rgbx(0x00, 0x00, 0xff, 0xff));
+
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        bmp.swap_channels([Channel::Blue, Channel::Green, Channel::Red]);
+        assert_eq!(bmp.colors[3], Rgbx(0x00, 0x00, 0xff, 0x00));
     }
 
     #[test]
-    #[should_fail(expected = "UnsupportedBpp")]
-    fn should_fail_read_unsupported_pixel_format() {
-        let buff: Vec<u8> = vec![
-            0x42, 0x04d, 
-            0x52, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-            0x46, 0x00, 0x00, 0x00,
+    fn should_access_indices() {
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.indices().len(), (bmp.dib.width * bmp.dib.height) as usize);
+        bmp.indices_mut()[0] = 0;
+        assert_eq!(bmp.row(2)[0], 0);
+    }
 
-            0x28, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x01, 0x00, 
-            0x01, 0x00, // <-- 0x0100 unsupported
-            0x00, 0x00, 0x00, 0x00,
-            0x0c, 0x00, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
+    #[test]
+    fn should_read_64bpp_pixels() {
+        let mut data = sample();
+        data.truncate(54);
+        data[10] = 0x36;
+        data[18] = 0x01;
+        data[22] = 0x01;
+        data[28] = 0x40;
+        data[34] = 0x08;
+        data[46] = 0x00;
+        data[50] = 0x00;
+        data.extend([0x34, 0x12, 0x78, 0x56, 0xbc, 0x9a, 0x00, 0x80].iter().cloned());
+        let bmp = Bitmap::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(bmp.dib.bpp, 64);
+        assert_eq!(bmp.color(0, 0), Rgbx(0x9a, 0x56, 0x12, 0x80));
+        assert!(bmp.has_transparency());
+    }
 
-            0x00, 0x00, 0x00, 0x00,
-            0xff, 0x00, 0x00, 0x00,
-            0x00, 0xff, 0x00, 0x00,
-            0x00, 0x00, 0xff, 0x00,
+    #[test]
+    fn should_report_header_version() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.header_version(), HeaderVersion::Info);
+        let bmp = Bitmap::read(&mut Cursor::new(sample_with_dib_extra(&[0; 84]))).unwrap();
+        assert_eq!(bmp.header_version(), HeaderVersion::V5);
+    }
 
-            0x13, 0x20, 0x00, 0x00,
-            0x30, 0x30, 0x00, 0x00,
-            0x23, 0x10, 0x00, 0x00,
-        ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+    #[test]
+    fn should_ensure_even_dimensions() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let even = bmp.ensure_even_dimensions(0);
+        assert_eq!((even.dib.width, even.dib.height), (4, 4));
+        assert_eq!(even.row(0), [2, 3, 1, 0]);
+        assert_eq!(even.row(2), [1, 3, 2, 0]);
+        assert_eq!(even.row(3), [0, 0, 0, 0]);
+        assert_eq!(even.ensure_even_dimensions(0), even);
     }
 
     #[test]
-    fn should_read() {
-        let buff: Vec<u8> = vec![
+    fn should_decode_rows_with_custom_decoder() {
+        struct Decoder8bpp;
+
+        impl RowDecoder for Decoder8bpp {
+            fn stride(&self, cols: usize) -> Option<usize> { Some(cols) }
+            fn decode_row(&self, row: &[u8], _: usize) -> Vec<usize> {
+                row.iter().map(|&b| b as usize).collect()
+            }
+        }
+
+        let data = [0x01, 0x02, 0xee, 0xee, 0x03, 0x04, 0xee, 0xee];
+        let mut pixels = Pixels::new();
+        Bitmap::decode_rows(&mut &data[..], 2, 2, 4, &Decoder8bpp, &mut pixels, &mut |_| {})
+            .unwrap();
+        assert_eq!(pixels, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn should_read_palette_after_dib_despite_gap() {
+        let mut data = sample();
+        data[2] += 8;
+        data[10] += 8;
+        let pixels = data.split_off(70);
+        data.extend([0xff; 8].iter().cloned());
+        data.extend(pixels);
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let bmp = Bitmap::read(&mut Cursor::new(data.clone())).unwrap();
+        assert_eq!(bmp.colors, expected.colors);
+        assert_eq!(bmp.pixels, expected.pixels);
+        assert_eq!(Bitmap::read_palette(&mut Cursor::new(data)).unwrap(), expected.colors);
+    }
+
+    #[test]
+    fn should_summarize_stats() {
+        let pixels = vec![
+            Rgbx(0x00, 0x00, 0x00, 0xff).to_pixel(), Rgbx(0xff, 0xff, 0xff, 0xff).to_pixel(),
+            Rgbx(0xff, 0xff, 0xff, 0xff).to_pixel(), Rgbx(0x80, 0x80, 0x80, 0x80).to_pixel()];
+        let bmp = Bitmap::new(2, 2, 32, ColorTable::new(), pixels).unwrap();
+        assert_eq!(bmp.stats(), ImageStats {
+            width: 2,
+            height: 2,
+            bpp: 32,
+            distinct_colors: 3,
+            min_luminance: 0,
+            max_luminance: 255,
+            mean_luminance: 159.5,
+            has_transparency: true,
+        });
+    }
+
+    #[test]
+    fn should_save_and_reload_1bpp() {
+        use std::env;
+        use std::fs;
+
+        let colors = vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0xff, 0xff, 0)];
+        let pixels = vec![1, 0, 1, 1, 0, 0, 0, 0, 1, 1, 1, 0, 1, 0, 1, 0, 1, 1];
+        let bmp = Bitmap::new(9, 2, 1, colors, pixels).unwrap();
+        let path = env::temp_dir().join("pbm-should-save-and-reload-1bpp.bmp");
+        bmp.save_as(&path, 1).unwrap();
+        let data = fs::read(&path).unwrap();
+        let saved = Bitmap::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), 14 + 40 + 2 * 4 + 2 * 4);
+        assert_eq!(&data[62..64], [0b10110000, 0b10000000]);
+        assert_eq!(saved.dib.bpp, 1);
+        assert_eq!(saved.colors, bmp.colors);
+        assert_eq!(saved.pixels, bmp.pixels);
+    }
+
+    #[test]
+    fn should_not_write_large_palette_at_1bpp() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert!(bmp.write(&mut Vec::new(), 1).is_err());
+    }
+
+    #[test]
+    fn should_not_write_anything_for_unencodable_image() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let mut out = Vec::new();
+        assert!(bmp.write(&mut out, 16).is_err());
+        assert!(out.is_empty());
+
+        let overflow = bmp.generate(3, 3, |x, _| x + 2);
+        assert!(overflow.write(&mut out, 2).is_err());
+        assert!(overflow.write(&mut out, 24).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn should_dither_deterministically() {
+        // Black and white are important, so they make the whole reduced palette
+        let mut colors = vec![Rgbx(0, 0, 0, 0), Rgbx(0xff, 0xff, 0xff, 0)];
+        colors.extend((0..8).map(|i| { let v = (i * 255 / 7) as u8; Rgbx(v, v, v, 0) }));
+        let pixels = (0..2).flat_map(|_| 2..10).collect();
+        let mut bmp = Bitmap::new(8, 2, 8, colors, pixels).unwrap();
+        bmp.dib.imp_colors = 2;
+
+        let opts = QuantizeOptions { dither: true };
+        let dithered = bmp.quantize_with_options(2, &opts);
+        assert_eq!(dithered.row(0), [0, 0, 0, 1, 0, 1, 1, 1]);
+        assert_eq!(dithered.row(1), [0, 0, 0, 1, 0, 1, 1, 1]);
+        assert_eq!(bmp.quantize_with_options(2, &opts), dithered);
+        assert_eq!(bmp.quantize(2).row(0), [0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn should_infer_colors_from_offset_when_lenient() {
+        let mut data = sample();
+        data[46] = 0xff;
+        data[47] = 0xff;
+        assert!(Bitmap::read(&mut Cursor::new(data.clone())).is_err());
+
+        let opts = DecodeOptions { lenient: true, ..Default::default() };
+        let bmp = Bitmap::read_with_options(&mut Cursor::new(data), &opts).unwrap();
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.dib.colors, 4);
+        assert_eq!(bmp.colors, expected.colors);
+        assert_eq!(bmp.pixels, expected.pixels);
+    }
+
+    #[test]
+    fn should_clamp_to_palette() {
+        let colors: ColorTable = (0..16).map(|i| Rgbx(i, i, i, 0)).collect();
+        let mut bmp = Bitmap::new(2, 1, 4, colors, vec![3, 15]).unwrap();
+        bmp.pixels[0] = 50;
+        bmp.clamp_to_palette();
+        assert_eq!(bmp.pixels, [15, 15]);
+    }
+
+    #[test]
+    fn should_read_animation_frames() {
+        fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut buff = id.to_vec();
+            put_dword(&mut buff, data.len() as u32);
+            buff.extend(data.iter().cloned());
+            if data.len() % 2 == 1 { buff.push(0); }
+            buff
+        }
+
+        let mut second = sample();
+        second[70] = 0x00;
+        let mut frames = b"fram".to_vec();
+        frames.extend(chunk(b"icon", &sample()));
+        frames.extend(chunk(b"icon", &second));
+        let mut body = b"ACON".to_vec();
+        body.extend(chunk(b"anih", &[0; 5]));
+        body.extend(chunk(b"LIST", &frames));
+        let data = chunk(b"RIFF", &body);
+
+        let bmps = Bitmap::read_frames(&mut Cursor::new(data)).unwrap();
+        assert_eq!(bmps.len(), 2);
+        assert_eq!(bmps[0], Bitmap::read(&mut Cursor::new(sample())).unwrap());
+        assert_eq!(bmps[1], Bitmap::read(&mut Cursor::new(second)).unwrap());
+    }
+
+    #[test]
+    fn should_render_html_table() {
+        let colors = vec![Rgbx(0xff, 0, 0, 0), Rgbx(0, 0x80, 0xff, 0)];
+        let bmp = Bitmap::new(2, 2, 1, colors, vec![0, 1, 1, 0]).unwrap();
+        assert_eq!(bmp.to_html_table(), "<table>\n\
+            <tr><td style=\"background:#0080ff\"></td><td style=\"background:#ff0000\"></td></tr>\n\
+            <tr><td style=\"background:#ff0000\"></td><td style=\"background:#0080ff\"></td></tr>\n\
+            </table>\n");
+    }
+
+    #[test]
+    fn should_limit_html_table_cells() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap().tile(30, 30).unwrap();
+        let html = bmp.to_html_table();
+        assert_eq!(html.matches("<tr>").count(), 45);
+        assert_eq!(html.matches("<td").count(), 45 * 45);
+    }
+
+    #[test]
+    fn should_resume_partial_decode() {
+        let data = sample();
+        let mut input = &data[..];
+        let state = Bitmap::read_partial(&mut input, 2).unwrap();
+        assert_eq!(state.rows_decoded(), 2);
+        assert!(!state.is_complete());
+        let state = Bitmap::resume_partial(&mut input, state, 2).unwrap();
+        assert_eq!(state.rows_decoded(), 3);
+        assert_eq!(state.into_bitmap().unwrap(), 
+                   Bitmap::read(&mut Cursor::new(sample())).unwrap());
+    }
+
+    #[test]
+    #[should_fail(expected = "UnsupportedCompression(4)")]
+    fn should_reject_unsupported_compression() {
+        let mut data = sample();
+        data[30] = 0x04;
+        Bitmap::read(&mut Cursor::new(data)).unwrap();
+    }
+
+    #[test]
+    fn should_build_bmp_bytes() {
+        let data = BmpBytesBuilder::new()
+            .width(3).height(3).bpp(4)
+            .color(0x00, 0x00, 0x00)
+            .color(0x00, 0x00, 0xff)
+            .color(0x00, 0xff, 0x00)
+            .color(0xff, 0x00, 0x00)
+            .pixel_row(&[1, 3, 2])
+            .pixel_row(&[3, 0, 3])
+            .pixel_row(&[2, 3, 1])
+            .build();
+        let bmp = Bitmap::read(&mut Cursor::new(data)).unwrap();
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.colors, expected.colors);
+        assert_eq!(bmp.pixels, expected.pixels);
+
+        let data = BmpBytesBuilder::new()
+            .width(2).height(1).bpp(32)
+            .pixel_row(&[Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel(), Rgbx(0, 0, 0, 0xff).to_pixel()])
+            .build();
+        let bmp = Bitmap::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(bmp.color(0, 0), Rgbx(0x10, 0x20, 0x30, 0xff));
+    }
+
+    #[test]
+    fn should_bleed_colors_into_transparent_pixels() {
+        let pixels = vec![Rgbx(0xff, 0, 0, 0xff).to_pixel(), Rgbx(0, 0, 0, 0).to_pixel(), 
+                          Rgbx(0, 0, 0, 0).to_pixel()];
+        let mut bmp = Bitmap::new(3, 1, 32, ColorTable::new(), pixels).unwrap();
+        bmp.alpha_bleed();
+        assert_eq!(Rgbx::from_pixel(bmp.pixels[1]), Rgbx(0xff, 0, 0, 0));
+        assert_eq!(Rgbx::from_pixel(bmp.pixels[2]), Rgbx(0xff, 0, 0, 0));
+        assert_eq!(Rgbx::from_pixel(bmp.pixels[0]), Rgbx(0xff, 0, 0, 0xff));
+    }
+
+    #[test]
+    fn should_drop_redundant_alpha() {
+        let pixels = vec![Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel(), Rgbx(0, 0, 0, 0xff).to_pixel()];
+        let bmp = Bitmap::new(2, 1, 32, ColorTable::new(), pixels).unwrap();
+        let dropped = bmp.drop_redundant_alpha();
+        assert_eq!(dropped.dib.bpp, 24);
+        assert_eq!(dropped.dib.size, 8);
+        assert_eq!(dropped.row_hashes(), bmp.row_hashes());
+
+        let mut translucent = bmp.clone();
+        translucent.pixels[1] = Rgbx(0, 0, 0, 0x80).to_pixel();
+        assert_eq!(translucent.drop_redundant_alpha(), translucent);
+    }
+
+    #[test]
+    #[should_fail(expected = "DimensionsTooLarge")]
+    fn should_fail_when_pixel_data_overflows() {
+        let mut data = sample();
+        for i in 18..25 { data[i] = 0xff; }
+        data[25] = 0x7f;
+        data[28] = 0x20;
+        Bitmap::read(&mut Cursor::new(data)).unwrap();
+    }
+
+    #[test]
+    fn should_compute_section_without_overflow() {
+        // The pixel data of a 2^16 x 2^16 image at 32 bpp overflows a 32-bit usize
+        match Bitmap::pixel_section(1 << 16, 1 << 16, 32, 4) {
+            Ok((rbytes, ebytes)) => assert_eq!((rbytes, ebytes as u64), (1 << 18, 1 << 34)),
+            Err(LoadError::DimensionsTooLarge) => assert!((::std::usize::MAX as u64) < 1 << 34),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn should_flatten_over_background() {
+        let pixels = vec![Rgbx(0xff, 0xff, 0xff, 0x80).to_pixel(), Rgbx(0x10, 0x20, 0x30, 0xff).to_pixel()];
+        let bmp = Bitmap::new(2, 1, 32, ColorTable::new(), pixels).unwrap();
+        let flat = bmp.flatten(Rgbx(0, 0, 0, 0xff));
+        assert_eq!(flat.color(0, 0), Rgbx(0x80, 0x80, 0x80, 0xff));
+        assert_eq!(flat.color(1, 0), Rgbx(0x10, 0x20, 0x30, 0xff));
+        assert!(!flat.has_transparency());
+    }
+
+    #[test]
+    fn should_read_zero_image_size() {
+        let mut data = sample();
+        data[34] = 0x00;
+        let opts = DecodeOptions { strict: true, ..Default::default() };
+        let bmp = Bitmap::read_with_options(&mut Cursor::new(data.clone()), &opts).unwrap();
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.pixels, expected.pixels);
+
+        let bmp = Bitmap::read_dib_only(&mut Cursor::new(data[14..].to_vec())).unwrap();
+        assert_eq!(bmp.header.size, expected.header.size);
+    }
+
+    #[test]
+    fn should_build_color_grid() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let grid = bmp.to_color_grid();
+        assert_eq!(grid.len(), 9);
+        assert_eq!(grid[1], Rgbx(0xff, 0, 0, 0xff));
+        assert_eq!(grid[2 * 3], Rgbx(0, 0, 0xff, 0xff));
+    }
+
+    #[test]
+    fn should_decode_into_rgba_buffer() {
+        let mut out = [0u8; 40];
+        let dims = Bitmap::decode_into_rgba(&mut Cursor::new(sample()), &mut out).unwrap();
+        assert_eq!(dims, (3, 3));
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let expected: Vec<u8> = bmp.to_color_grid().iter()
+            .flat_map(|c| vec![c.0, c.1, c.2, c.3])
+            .collect();
+        assert_eq!(&out[..36], &expected[..]);
+        assert_eq!(&out[36..], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    #[should_fail(expected = "BufferTooSmall(36, 35)")]
+    fn should_fail_decode_into_small_buffer() {
+        let mut out = [0u8; 35];
+        Bitmap::decode_into_rgba(&mut Cursor::new(sample()), &mut out).unwrap();
+    }
+
+    #[test]
+    fn should_keep_raw_v5_dib() {
+        let mut data = sample();
+        let mut v5 = vec![0u8; 124 - 40];
+        for (i, b) in v5.iter_mut().enumerate() { *b = i as u8; }
+        data[14] = 124;
+        data[10] += 124 - 40;
+        for (i, &b) in v5.iter().enumerate() { data.insert(54 + i, b); }
+        let bmp = Bitmap::read(&mut Cursor::new(data.clone())).unwrap();
+        assert_eq!(bmp.raw_dib, &data[14..14 + 124]);
+        assert_eq!(bmp.dib.extra, v5);
+    }
+
+    #[test]
+    fn should_count_transparent_pixels() {
+        let pixels = vec![Rgbx(0, 0, 0, 0).to_pixel(), Rgbx(0xff, 0, 0, 0x80).to_pixel(),
+                          Rgbx(0xff, 0xff, 0xff, 0).to_pixel(), Rgbx(0, 0, 0, 0xff).to_pixel()];
+        let bmp = Bitmap::new(2, 2, 32, ColorTable::new(), pixels).unwrap();
+        assert_eq!(bmp.count_transparent_pixels(), 2);
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.count_transparent_pixels(), 0);
+    }
+
+    #[test]
+    fn should_read_zero_planes_leniently() {
+        let mut data = sample();
+        data[26] = 0x00;
+        assert!(Bitmap::read(&mut Cursor::new(data.clone())).is_err());
+        let opts = DecodeOptions { lenient: true, ..Default::default() };
+        let bmp = Bitmap::read_with_options(&mut Cursor::new(data), &opts).unwrap();
+        assert_eq!(bmp.dib.planes, 1);
+        assert_eq!(bmp.pixels, Bitmap::read(&mut Cursor::new(sample())).unwrap().pixels);
+    }
+
+    #[test]
+    #[should_fail(expected = "InvalidPlanes(2)")]
+    fn should_fail_read_several_planes() {
+        let mut data = sample();
+        data[26] = 0x02;
+        let opts = DecodeOptions { lenient: true, ..Default::default() };
+        Bitmap::read_with_options(&mut Cursor::new(data), &opts).unwrap();
+    }
+
+    #[test]
+    fn should_read_top_rows() {
+        let bmp = Bitmap::read_top_rows(&mut Cursor::new(sample()), 1).unwrap();
+        assert_eq!((bmp.dib.width, bmp.dib.height), (3, 1));
+        assert_eq!(bmp.pixels, [2, 3, 1]);
+
+        let mut data = sample();
+        for i in 22..26 { data[i] = 0xff; }
+        data[22] = 0xfd;
+        let bmp = Bitmap::read_top_rows(&mut Cursor::new(data), 1).unwrap();
+        assert_eq!(bmp.pixels, [1, 3, 2]);
+        assert_eq!(bmp.color(0, 0), Rgbx(0, 0, 0xff, 0xff));
+
+        let bmp = Bitmap::read_top_rows(&mut Cursor::new(sample()), 5).unwrap();
+        assert_eq!(bmp, Bitmap::read(&mut Cursor::new(sample())).unwrap());
+    }
+
+    #[test]
+    fn should_equalize() {
+        let pixels = [100, 110, 120, 130].iter()
+            .map(|&v| Rgbx(v, v, v, 0xff).to_pixel())
+            .collect();
+        let mut bmp = Bitmap::new(2, 2, 32, ColorTable::new(), pixels).unwrap();
+        bmp.equalize();
+        let stats = bmp.stats();
+        assert_eq!((stats.min_luminance, stats.max_luminance), (0, 255));
+        assert!(bmp.is_grayscale());
+    }
+
+    #[test]
+    fn should_honor_options_in_every_reader() {
+        let mut data = sample();
+        let fields = [(2, 4), (6, 4), (10, 4), (14, 4), (18, 4), (22, 4), (26, 2), 
+                      (28, 2), (30, 4), (34, 4), (38, 4), (42, 4), (46, 4), (50, 4)];
+        for &(i, n) in fields.iter() { data[i..i + n].reverse(); }
+        let opts = DecodeOptions { byte_order: ByteOrder::Big, ..Default::default() };
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+
+        let state = Bitmap::read_partial_with_options(&mut Cursor::new(data.clone()), 3, &opts);
+        assert_eq!(state.unwrap().into_bitmap().unwrap().pixels, expected.pixels);
+
+        let mut out = [0u8; 36];
+        let dims = Bitmap::decode_into_rgba_with_options(&mut Cursor::new(data), &mut out, &opts);
+        assert_eq!(dims.unwrap(), (3, 3));
+        assert_eq!(&out[..4], [0x00, 0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn should_read_core_header() {
+        let mut data: Vec<u8> = vec![
             0x42, 0x04d, 
-            0x52, 0x00, 0x00, 0x00,
+            0x56, 0x00, 0x00, 0x00,
             0x00, 0x00, 0x00, 0x00,
-            0x46, 0x00, 0x00, 0x00,
+            0x4a, 0x00, 0x00, 0x00, // <-- 14 + 12 + 16 * 3
 
-            0x28, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
-            0x03, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00,
+            0x03, 0x00, // <-- 16-bit width
+            0x03, 0x00, // <-- 16-bit height
             0x01, 0x00, 
             0x04, 0x00, 
-            0x00, 0x00, 0x00, 0x00,
-            0x0c, 0x00, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x13, 0x0b, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
-            0x04, 0x00, 0x00, 0x00,
 
-            0x00, 0x00, 0x00, 0x00,
-            0xff, 0x00, 0x00, 0x00,
-            0x00, 0xff, 0x00, 0x00,
-            0x00, 0x00, 0xff, 0x00,
+            0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00,
+            0x00, 0xff, 0x00,
+            0x00, 0x00, 0xff,
+        ];
+        data.extend(vec![0x80; 3 * 12]);
+        data.extend(&sample()[70..82]);
+        let bmp = Bitmap::read(&mut Cursor::new(data.clone())).unwrap();
+        let expected = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        assert_eq!(bmp.header_version(), HeaderVersion::Core);
+        assert_eq!((bmp.dib.width, bmp.dib.height), (3, 3));
+        assert_eq!(bmp.colors.len(), 16);
+        assert_eq!(&bmp.colors[..4], &expected.colors[..]);
+        assert_eq!(bmp.colors[4], Rgbx(0x80, 0x80, 0x80, 0));
+        assert_eq!(bmp.pixels, expected.pixels);
+        assert_eq!(bmp.raw_dib, &data[14..26]);
 
-            0x13, 0x20, 0x00, 0x00,
-            0x30, 0x30, 0x00, 0x00,
-            0x23, 0x10, 0x00, 0x00,
+        // The pixels cannot start before the 3 byte entries end
+        data[10] -= 1;
+        assert!(Bitmap::read(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn should_drop_raw_dib_when_format_changes() {
+        let mut extra = vec![0u8; 84];
+        extra[68] = 0x04;
+        let mut bmp = Bitmap::read(&mut Cursor::new(sample_with_dib_extra(&extra))).unwrap();
+        bmp.invert();
+        assert_eq!(bmp.raw_dib.len(), 124);
+
+        let changed = vec![
+            bmp.to_direct(), 
+            bmp.to_direct().quantize(2), 
+            bmp.to_direct().drop_redundant_alpha(), 
+            bmp.subsample(2).unwrap(),
         ];
-        Bitmap::read(&mut Cursor::new(buff)).unwrap();
+        for b in changed.iter() {
+            assert!(b.raw_dib.is_empty());
+            assert!(b.dib.extra.is_empty());
+            assert_eq!(b.header_version(), HeaderVersion::Info);
+            assert_eq!(b.header.offset, 14 + 40 + 4 * b.colors.len() as u32);
+        }
+
+        let data = sample_with_dib_extra(&extra);
+        let top = Bitmap::read_top_rows(&mut Cursor::new(data.clone()), 1).unwrap();
+        assert!(top.raw_dib.is_empty());
+        let all = Bitmap::read_top_rows(&mut Cursor::new(data), 3).unwrap();
+        assert_eq!(all.raw_dib.len(), 124);
+    }
+
+    #[test]
+    fn should_equalize_with_unused_dark_color() {
+        let colors = vec![Rgbx(0, 0, 0, 0), Rgbx(100, 100, 100, 0), Rgbx(120, 120, 120, 0)];
+        let mut bmp = Bitmap::new(2, 1, 4, colors, vec![1, 2]).unwrap();
+        bmp.equalize();
+        assert_eq!(bmp.colors[0], Rgbx(0, 0, 0, 0));
+        assert_eq!(bmp.colors[1], Rgbx(0, 0, 0, 0));
+        assert_eq!(bmp.colors[2], Rgbx(255, 255, 255, 0));
+    }
+
+    #[test]
+    fn should_clamp_quantized_colors() {
+        let pixels = (0..300).map(|i| Rgbx(i as u8, (i >> 8) as u8, 0, 0xff).to_pixel()).collect();
+        let bmp = Bitmap::new(300, 1, 24, ColorTable::new(), pixels).unwrap();
+        let many = bmp.quantize(1000);
+        assert_eq!((many.dib.bpp, many.colors.len()), (8, 256));
+        let none = bmp.quantize(0);
+        assert_eq!(none.colors.len(), 1);
+        assert!(none.pixels.iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn should_ignore_palette_entries_past_256() {
+        let bmp = Bitmap::new(1, 1, 24, ColorTable::new(), 
+                              vec![Rgbx(0xff, 0xff, 0xff, 0xff).to_pixel()]).unwrap();
+        let palette: ColorTable = (0..300).map(|i| Rgbx(i as u8, (i >> 8) as u8, 0, 0)).collect();
+        let indexed = bmp.to_indexed_with_palette(&palette);
+        assert_eq!((indexed.dib.bpp, indexed.colors.len()), (8, 256));
+        assert_eq!(indexed.pixels, [255]);
+    }
+
+    #[test]
+    fn should_detect_core_header_bmp() {
+        let data: Vec<u8> = vec![
+            0x42, 0x04d, 
+            0x56, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x4a, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00, // <-- BITMAPCOREHEADER
+        ];
+        assert!(is_bmp(&data));
+    }
+
+    #[test]
+    fn should_subsample_by_huge_factor() {
+        let bmp = Bitmap::read(&mut Cursor::new(sample())).unwrap();
+        let sub = bmp.subsample(::std::u32::MAX).unwrap();
+        assert_eq!((sub.dib.width, sub.dib.height), (1, 1));
+        assert_eq!(sub.pixels, [bmp.row(0)[0]]);
     }
 }
@@ -0,0 +1,43 @@
+//
+// SimProc library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+#![feature(fs)]
+#![feature(io)]
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "image-crate")]
+extern crate image;
+
+#[cfg(feature = "gzip")]
+extern crate flate2;
+
+#[cfg(feature = "png")]
+extern crate png;
+
+#[cfg(feature = "async")]
+extern crate tokio;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+
+pub mod bmp;
+pub mod netpbm;
+
+pub use bmp::is_bmp;
+
+/// The result of loading or reading a bitmap
+///
+/// ```no_run
+/// fn load_width(filename: &str) -> pbm::Result<u32> {
+///     let bmp = try!(pbm::bmp::Bitmap::load(filename));
+///     Ok(bmp.dib.width)
+/// }
+/// ```
+pub type Result<T> = ::std::result::Result<T, bmp::LoadError>;
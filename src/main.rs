@@ -6,10 +6,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-#![feature(fs)]
-#![feature(io)]
+extern crate pbm;
 
-mod bmp;
+use pbm::bmp;
 
 fn main() {
     let img = match bmp::Bitmap::load("/tmp/foo2.bmp") {
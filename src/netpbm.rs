@@ -0,0 +1,212 @@
+//
+// SimProc library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+
+use bmp::{Bitmap, Dib, Header, HeaderVersion, LoadError, Pixels, Rgbx};
+
+/// A cursor over the contents of a PNM file
+struct Parser {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Parser {
+
+    fn skip_space(&mut self) {
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+                b'#' => while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+                    self.pos += 1;
+                },
+                _ => break,
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, LoadError> {
+        self.skip_space();
+        let start = self.pos;
+        let mut n = 0u32;
+        while self.pos < self.data.len() {
+            let c = self.data[self.pos];
+            if c < b'0' || c > b'9' { break }
+            n = try!(n.checked_mul(10)
+                .and_then(|n| n.checked_add((c - b'0') as u32))
+                .ok_or(LoadError::InvalidPnm));
+            self.pos += 1;
+        }
+        if self.pos == start {
+            if self.pos == self.data.len() { Err(LoadError::UnexpectedEof) }
+            else { Err(LoadError::InvalidPnm) }
+        } else { Ok(n) }
+    }
+
+    fn bit(&mut self) -> Result<u32, LoadError> {
+        // Plain PBM bits need no separating whitespace
+        self.skip_space();
+        let c = try!(self.byte());
+        match c {
+            b'0' => Ok(0),
+            b'1' => Ok(1),
+            _ => Err(LoadError::InvalidPnm),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        if self.pos == self.data.len() { return Err(LoadError::UnexpectedEof)}
+        self.pos += 1;
+        Ok(self.data[self.pos - 1])
+    }
+
+    fn sample(&mut self, maxval: u32) -> Result<u32, LoadError> {
+        let hi = try!(self.byte()) as u32;
+        if maxval < 256 { Ok(hi) }
+        else { Ok((hi << 8) | try!(self.byte()) as u32) }
+    }
+}
+
+impl Bitmap {
+
+    /// Read a PBM, PGM or PPM image (P1 to P6) as a direct-color bitmap
+    pub fn read_pnm<R: io::Read>(input: &mut R) -> ::Result<Bitmap> {
+        let mut data = Vec::new();
+        try!(input.read_to_end(&mut data));
+        if data.len() < 2 || data[0] != b'P' || data[1] < b'1' || data[1] > b'6' {
+            return Err(LoadError::BadMagic)
+        }
+        let kind = data[1];
+        let mut parser = Parser { data: data, pos: 2 };
+
+        let width = try!(parser.number());
+        let height = try!(parser.number());
+        let maxval = if kind == b'1' || kind == b'4' { 1 } else { try!(parser.number()) };
+        if maxval == 0 || maxval > 65535 { return Err(LoadError::InvalidPnm)}
+
+        // Binary formats have a single whitespace between the header and the raster
+        if kind >= b'4' { try!(parser.byte()); }
+
+        // Grow the pixels as samples arrive so bogus dimensions cannot force a huge 
+        // allocation, but refuse dimensions whose pixel data cannot even be sized
+        let (cols, rows) = (width as usize, height as usize);
+        let npixels = try!(cols.checked_mul(rows).ok_or(LoadError::InvalidPnm));
+        let stride = (24 * width as u64 + 31) / 32 * 4;
+        let size = match stride.checked_mul(height as u64) {
+            Some(n) if n <= (::std::u32::MAX - 54) as u64 => n as u32,
+            _ => return Err(LoadError::InvalidPnm),
+        };
+        let mut colors = Vec::new();
+        for _ in 0..rows {
+            let mut bits = 0u8;
+            for c in 0..cols {
+                let (r, g, b) = match kind {
+                    b'1' | b'4' => {
+                        let bit = if kind == b'1' { try!(parser.bit()) } else {
+                            if c % 8 == 0 { bits = try!(parser.byte()); }
+                            ((bits >> (7 - c % 8)) & 1) as u32
+                        };
+                        // A set bit is black
+                        let v = 1 - bit;
+                        (v, v, v)
+                    },
+                    b'2' => { let v = try!(parser.number()); (v, v, v) },
+                    b'5' => { let v = try!(parser.sample(maxval)); (v, v, v) },
+                    b'3' => (try!(parser.number()), try!(parser.number()), try!(parser.number())),
+                    _ => (try!(parser.sample(maxval)), try!(parser.sample(maxval)), 
+                          try!(parser.sample(maxval))),
+                };
+                if r > maxval || g > maxval || b > maxval { return Err(LoadError::InvalidPnm)}
+                colors.push(Rgbx(
+                    (r * 255 / maxval) as u8, 
+                    (g * 255 / maxval) as u8, 
+                    (b * 255 / maxval) as u8, 
+                    0xff));
+            }
+        }
+
+        // Store the rows bottom-up as BMP does
+        let mut pixels = Pixels::with_capacity(npixels);
+        for r in (0..rows).rev() {
+            pixels.extend(colors[r * cols..(r + 1) * cols].iter().map(|c| c.to_pixel()));
+        }
+
+        Ok(Bitmap {
+            header: Header { size: 14 + 40 + size, reserved: 0, offset: 14 + 40 },
+            dib: Dib {
+                width: width,
+                height: height,
+                planes: 1,
+                bpp: 24,
+                comp: 0,
+                size: size,
+                ppm_x: 0,
+                ppm_y: 0,
+                colors: 0,
+                imp_colors: 0,
+                rgb_masks: None,
+                alpha_mask: None,
+                gamma: None,
+                intent: None,
+                top_down: false,
+                extra: Vec::new(),
+                version: HeaderVersion::Info,
+            },
+            colors: Vec::new(),
+            pixels: pixels,
+            raw_alpha: None,
+            raw_dib: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::Cursor;
+
+    use bmp::*;
+
+    #[test]
+    fn should_read_ascii_ppm() {
+        let buff = b"P3\n# a comment\n2 2\n255\n255 0 0  0 255 0\n0 0 255  255 255 255\n";
+        let bmp = Bitmap::read_pnm(&mut Cursor::new(&buff[..])).unwrap();
+        assert_eq!(bmp.dib.width, 2);
+        assert_eq!(bmp.dib.height, 2);
+        assert_eq!(bmp.pixels, vec![
+            Rgbx(0, 0, 255, 0xff).to_pixel(), Rgbx(255, 255, 255, 0xff).to_pixel(),
+            Rgbx(255, 0, 0, 0xff).to_pixel(), Rgbx(0, 255, 0, 0xff).to_pixel(),
+        ]);
+    }
+
+    #[test]
+    fn should_read_binary_ppm() {
+        let buff = b"P6 2 1 15\n\x0f\x00\x00\x00\x05\x0f";
+        let bmp = Bitmap::read_pnm(&mut Cursor::new(&buff[..])).unwrap();
+        assert_eq!(bmp.dib.width, 2);
+        assert_eq!(bmp.dib.height, 1);
+        assert_eq!(bmp.pixels, vec![
+            Rgbx(255, 0, 0, 0xff).to_pixel(), Rgbx(0, 85, 255, 0xff).to_pixel(),
+        ]);
+    }
+
+    #[test]
+    #[should_fail(expected = "UnexpectedEof")]
+    fn should_fail_read_truncated_ppm() {
+        let buff = b"P6 2 1 255\n\xff\x00\x00\x00";
+        Bitmap::read_pnm(&mut Cursor::new(&buff[..])).unwrap();
+    }
+
+    #[test]
+    fn should_fail_read_ppm_with_huge_dimensions() {
+        let buff = b"P6 4294967295 4294967295 255\n\x00";
+        assert!(Bitmap::read_pnm(&mut Cursor::new(&buff[..])).is_err());
+        let buff = b"P6 100000 100000 255\n\x00";
+        assert!(Bitmap::read_pnm(&mut Cursor::new(&buff[..])).is_err());
+    }
+}